@@ -2,6 +2,7 @@ use anyhow::Result;
 use axum::extract::State;
 use axum::routing::{get, patch};
 use axum::Json;
+use ecosystem::shutdown;
 use serde::de::{MapAccess, SeqAccess, Visitor};
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -121,7 +122,12 @@ async fn main() -> Result<()> {
         .route("/", get(handle_user))
         .route("/", patch(update_user))
         .with_state(Arc::new(Mutex::new(user)));
-    axum::serve(listener, router.into_make_service()).await?;
+    // 收到 SIGINT/SIGTERM 后处理完正在处理的请求再退出
+    let shutdown = shutdown::on_signal();
+    axum::serve(listener, router.into_make_service())
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    info!("server stopped");
     Ok(())
 }
 
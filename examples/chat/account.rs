@@ -0,0 +1,204 @@
+use crate::config::AccountStoreConfig;
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::info;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    // 注册时的写法, 查找时不区分大小写
+    pub username: String,
+    // argon2 的 PHC 字符串, 里面带着 salt 和参数
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// 账号存储, 默认是本地文件, 也可以放到 Postgres
+pub trait AccountStore: Debug + Send + Sync {
+    fn get<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<Account>>>;
+    /// 名字已经被注册时返回 false
+    fn create(&self, account: Account) -> BoxFuture<'_, Result<bool>>;
+}
+
+pub async fn open(config: &AccountStoreConfig) -> Result<Arc<dyn AccountStore>> {
+    let store: Arc<dyn AccountStore> = match config {
+        AccountStoreConfig::File(path) => Arc::new(FileStore::open(path.clone()).await?),
+        AccountStoreConfig::Postgres(url) => Arc::new(PgStore::connect(url).await?),
+    };
+    Ok(store)
+}
+
+impl Account {
+    pub async fn new(username: &str, password: &str) -> Result<Self> {
+        Ok(Self {
+            username: username.to_string(),
+            password_hash: hash_password(password).await?,
+            created_at: Utc::now(),
+        })
+    }
+
+    pub async fn verify(&self, password: &str) -> Result<bool> {
+        verify_password(password, &self.password_hash).await
+    }
+}
+
+/// argon2 故意算得很慢, 放到 blocking 线程里去做
+async fn hash_password(password: &str) -> Result<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow!("failed to hash password: {}", e))?;
+        Ok(hash.to_string())
+    })
+    .await?
+}
+
+async fn verify_password(password: &str, hash: &str) -> Result<bool> {
+    let password = password.to_string();
+    let hash = hash.to_string();
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash).map_err(|e| anyhow!("invalid password hash: {}", e))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await?
+}
+
+/// 整个文件是一个 JSON 数组, 启动时读进内存, 注册时整个重写
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    // lowercase username -> account
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl FileStore {
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        let accounts: Vec<Account> = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        info!("Loaded {} accounts from {}", accounts.len(), path.display());
+        let accounts = accounts
+            .into_iter()
+            .map(|account| (account.username.to_lowercase(), account))
+            .collect();
+        Ok(Self {
+            path,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    /// 先写临时文件再 rename, 写到一半挂掉也不会把文件弄坏
+    async fn save(&self, accounts: &HashMap<String, Account>) -> Result<()> {
+        let mut list: Vec<&Account> = accounts.values().collect();
+        list.sort_by_key(|account| account.created_at);
+        let data = serde_json::to_vec_pretty(&list)?;
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+impl AccountStore for FileStore {
+    fn get<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<Account>>> {
+        Box::pin(async move {
+            let accounts = self.accounts.lock().await;
+            Ok(accounts.get(&username.to_lowercase()).cloned())
+        })
+    }
+
+    fn create(&self, account: Account) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(async move {
+            let key = account.username.to_lowercase();
+            // 写文件的时候一直拿着锁, 保证同时注册时不会互相覆盖
+            let mut accounts = self.accounts.lock().await;
+            if accounts.contains_key(&key) {
+                return Ok(false);
+            }
+            accounts.insert(key.clone(), account);
+            if let Err(e) = self.save(&accounts).await {
+                accounts.remove(&key);
+                return Err(e);
+            }
+            Ok(true)
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct PgStore {
+    db: PgPool,
+}
+
+impl PgStore {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let pool = PgPool::connect(url).await?;
+        // name 是小写的用户名, 用来保证不区分大小写的唯一
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS chat_accounts (
+                name TEXT PRIMARY KEY,
+                username TEXT NOT NULL,
+                password_hash TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL
+            );
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { db: pool })
+    }
+}
+
+impl AccountStore for PgStore {
+    fn get<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<Account>>> {
+        Box::pin(async move {
+            let record: Option<(String, String, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT username, password_hash, created_at FROM chat_accounts WHERE name = $1",
+            )
+            .bind(username.to_lowercase())
+            .fetch_optional(&self.db)
+            .await?;
+            Ok(record.map(|(username, password_hash, created_at)| Account {
+                username,
+                password_hash,
+                created_at,
+            }))
+        })
+    }
+
+    fn create(&self, account: Account) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(async move {
+            let result = sqlx::query(
+                "INSERT INTO chat_accounts (name, username, password_hash, created_at) \
+                 VALUES ($1, $2, $3, $4) ON CONFLICT (name) DO NOTHING",
+            )
+            .bind(account.username.to_lowercase())
+            .bind(&account.username)
+            .bind(&account.password_hash)
+            .bind(account.created_at)
+            .execute(&self.db)
+            .await?;
+            Ok(result.rows_affected() == 1)
+        })
+    }
+}
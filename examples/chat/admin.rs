@@ -0,0 +1,183 @@
+use crate::message::SystemMessage;
+use crate::state::State;
+use axum::extract::{Path, Query, Request, State as AxumState};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::info;
+
+// 公告里显示的发送者
+const ADMIN_NAME: &str = "admin";
+
+#[derive(Clone)]
+struct AdminState {
+    state: Arc<State>,
+    // 只保存 hash, 比较时是常数时间的
+    token: blake3::Hash,
+}
+
+#[derive(Debug, Serialize)]
+struct PeerInfo {
+    addr: SocketAddr,
+    username: String,
+    account: Option<String>,
+    presence: String,
+    rooms: Vec<String>,
+    connected_at: DateTime<Utc>,
+    // 还没发出去的条数
+    queued: usize,
+    // 队列满了一共丢了多少条
+    dropped: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct RoomInfo {
+    name: String,
+    members: Vec<String>,
+    topic: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DisconnectParams {
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Announcement {
+    text: String,
+}
+
+/// 运维用的 HTTP 接口, 和聊天共用同一个 State, 所有请求都要带 token
+pub fn router(state: Arc<State>, token: &str) -> Router {
+    let admin = AdminState {
+        state,
+        token: blake3::hash(token.as_bytes()),
+    };
+    Router::new()
+        .route("/peers", get(list_peers))
+        .route("/peers/{addr}", delete(disconnect_peer))
+        .route("/rooms", get(list_rooms))
+        .route("/announce", post(announce))
+        .route_layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin)
+}
+
+async fn authorize(
+    AxumState(admin): AxumState<AdminState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| blake3::hash(token.as_bytes()) == admin.token);
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn list_peers(AxumState(admin): AxumState<AdminState>) -> Json<Vec<PeerInfo>> {
+    let state = &admin.state;
+    // 先把 peers 收集出来, 查房间时不要拿着 dashmap 的锁
+    let mut peers: Vec<PeerInfo> = state
+        .peers
+        .iter()
+        .map(|peer| PeerInfo {
+            addr: *peer.key(),
+            username: peer.username.clone(),
+            account: peer.account.clone(),
+            presence: peer.presence.to_string(),
+            rooms: vec![],
+            connected_at: peer.connected_at,
+            queued: peer.outbox.queued(),
+            dropped: peer.outbox.dropped(),
+        })
+        .collect();
+    for peer in peers.iter_mut() {
+        peer.rooms = state.rooms_of(peer.addr);
+        peer.rooms.sort();
+    }
+    peers.sort_by_key(|peer| peer.connected_at);
+    Json(peers)
+}
+
+async fn list_rooms(AxumState(admin): AxumState<AdminState>) -> Json<Vec<RoomInfo>> {
+    let state = &admin.state;
+    let rooms = state
+        .room_list()
+        .into_iter()
+        .map(|(name, _)| RoomInfo {
+            members: state
+                .members(&name)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(username, _)| username)
+                .collect(),
+            topic: state.topic(&name),
+            name,
+        })
+        .collect();
+    Json(rooms)
+}
+
+async fn disconnect_peer(
+    AxumState(admin): AxumState<AdminState>,
+    Path(addr): Path<SocketAddr>,
+    Query(params): Query<DisconnectParams>,
+) -> StatusCode {
+    let Some(username) = admin
+        .state
+        .peers
+        .get(&addr)
+        .map(|peer| peer.username.clone())
+    else {
+        return StatusCode::NOT_FOUND;
+    };
+    let reason = match &params.reason {
+        Some(reason) => format!("Disconnected by {}: {}", ADMIN_NAME, reason),
+        None => format!("Disconnected by {}", ADMIN_NAME),
+    };
+    info!(
+        target: "audit",
+        action = "disconnect",
+        operator = ADMIN_NAME,
+        subject = %username,
+        addr = %addr,
+        reason = params.reason.as_deref().unwrap_or_default()
+    );
+    admin.state.disconnect(addr, &reason);
+    StatusCode::NO_CONTENT
+}
+
+async fn announce(
+    AxumState(admin): AxumState<AdminState>,
+    Json(announcement): Json<Announcement>,
+) -> StatusCode {
+    let text = announcement.text.trim();
+    if text.is_empty() {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    info!(
+        target: "audit",
+        action = "announce",
+        operator = ADMIN_NAME,
+        text = %text
+    );
+    admin
+        .state
+        .broadcast_system(SystemMessage::Announce {
+            by: ADMIN_NAME.to_string(),
+            text: text.to_string(),
+        })
+        .await;
+    StatusCode::NO_CONTENT
+}
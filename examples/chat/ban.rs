@@ -0,0 +1,280 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    // 操作的 operator
+    pub by: String,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // None 表示永久
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// 按用户名 (不区分大小写) 或者 IP/CIDR
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BanTarget {
+    User(String),
+    Net(IpNet),
+}
+
+/// ban 列表, 存在一个 JSON 文件里, 文件被外部修改后下次检查时重新读
+#[derive(Debug)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    bans: Vec<Ban>,
+    // 上次读/写文件时的修改时间
+    modified: Option<SystemTime>,
+}
+
+impl BanTarget {
+    pub fn matches(&self, ip: IpAddr, username: Option<&str>) -> bool {
+        match self {
+            BanTarget::User(name) => username.is_some_and(|u| u.eq_ignore_ascii_case(name)),
+            BanTarget::Net(net) => net.contains(&ip),
+        }
+    }
+}
+
+/// 先当成 CIDR, 再当成单个 IP, 都不是就是用户名
+impl FromStr for BanTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Self::Net(net.trunc()));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Self::Net(IpNet::from(ip)));
+        }
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            return Err(anyhow!("invalid ban target {}", s));
+        }
+        Ok(Self::User(s.to_string()))
+    }
+}
+
+impl Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::User(name) => write!(f, "{}", name),
+            BanTarget::Net(net) => write!(f, "{}", net),
+        }
+    }
+}
+
+impl Ban {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// 发给被 ban 的人看的
+    pub fn describe(&self) -> String {
+        let mut text = format!("You are banned{}", until(self.expires_at));
+        if let Some(reason) = &self.reason {
+            text.push_str(&format!(": {}", reason));
+        }
+        text
+    }
+}
+
+/// 过期时间的描述, 永久时为空
+pub fn until(expires_at: Option<DateTime<Utc>>) -> String {
+    match expires_at {
+        Some(at) => format!(" until {}", at.format("%Y-%m-%d %H:%M:%S UTC")),
+        None => String::new(),
+    }
+}
+
+impl BanList {
+    pub async fn open(path: Option<PathBuf>) -> Result<Self> {
+        let list = Self {
+            path,
+            inner: Mutex::new(Inner::default()),
+        };
+        if let Some(path) = &list.path {
+            let mut inner = list.inner.lock().await;
+            list.reload(&mut inner).await?;
+            info!("Loaded {} bans from {}", inner.bans.len(), path.display());
+        }
+        Ok(list)
+    }
+
+    /// 连接进来时检查, 返回命中的 ban
+    pub async fn check(&self, ip: IpAddr, username: Option<&str>) -> Option<Ban> {
+        let mut inner = self.inner.lock().await;
+        if let Err(e) = self.reload(&mut inner).await {
+            warn!("Failed to reload ban list, keep using the old one: {:#}", e);
+        }
+        let now = Utc::now();
+        inner
+            .bans
+            .iter()
+            .find(|ban| !ban.is_expired(now) && ban.target.matches(ip, username))
+            .cloned()
+    }
+
+    /// 同一个 target 已经有 ban 时替换掉
+    pub async fn add(&self, ban: Ban) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        let now = Utc::now();
+        inner
+            .bans
+            .retain(|old| old.target != ban.target && !old.is_expired(now));
+        inner.bans.push(ban);
+        self.save(&mut inner).await
+    }
+
+    /// 返回 false 表示本来就没有
+    pub async fn remove(&self, target: &BanTarget) -> Result<bool> {
+        let mut inner = self.inner.lock().await;
+        let len = inner.bans.len();
+        inner.bans.retain(|ban| &ban.target != target);
+        if inner.bans.len() == len {
+            return Ok(false);
+        }
+        self.save(&mut inner).await?;
+        Ok(true)
+    }
+
+    /// 文件的修改时间变了才重新读
+    async fn reload(&self, inner: &mut Inner) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let modified = match tokio::fs::metadata(path).await {
+            Ok(metadata) => Some(metadata.modified()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if modified == inner.modified {
+            return Ok(());
+        }
+        inner.bans = match modified {
+            Some(_) => serde_json::from_slice(&tokio::fs::read(path).await?)?,
+            None => vec![],
+        };
+        inner.modified = modified;
+        Ok(())
+    }
+
+    async fn save(&self, inner: &mut Inner) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(&inner.bans)?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        inner.modified = Some(tokio::fs::metadata(path).await?.modified()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    fn ban(target: &str, expires_at: Option<DateTime<Utc>>) -> Ban {
+        Ban {
+            target: target.parse().unwrap(),
+            by: "op".to_string(),
+            reason: None,
+            created_at: Utc::now(),
+            expires_at,
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn target_should_parse_cidr_ip_and_user() {
+        let net: BanTarget = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(net, BanTarget::Net("10.1.0.0/16".parse().unwrap()));
+        let single: BanTarget = "10.1.2.3".parse().unwrap();
+        assert_eq!(single, BanTarget::Net("10.1.2.3/32".parse().unwrap()));
+        let v6: BanTarget = "::1".parse().unwrap();
+        assert_eq!(v6, BanTarget::Net("::1/128".parse().unwrap()));
+        let user: BanTarget = "Alice".parse().unwrap();
+        assert_eq!(user, BanTarget::User("Alice".to_string()));
+        // 不是合法的 CIDR 就当成用户名
+        let user: BanTarget = "10.1.2.3/99".parse().unwrap();
+        assert_eq!(user, BanTarget::User("10.1.2.3/99".to_string()));
+    }
+
+    #[test]
+    fn target_should_reject_empty_and_whitespace() {
+        assert!("".parse::<BanTarget>().is_err());
+        assert!("al ice".parse::<BanTarget>().is_err());
+    }
+
+    #[test]
+    fn target_should_match() {
+        let net: BanTarget = "10.1.0.0/16".parse().unwrap();
+        assert!(net.matches(ip("10.1.200.7"), None));
+        assert!(!net.matches(ip("10.2.0.1"), Some("alice")));
+        let user: BanTarget = "Alice".parse().unwrap();
+        assert!(user.matches(ip("10.2.0.1"), Some("aLiCe")));
+        assert!(!user.matches(ip("10.2.0.1"), None));
+        assert!(!user.matches(ip("10.2.0.1"), Some("bob")));
+    }
+
+    #[test]
+    fn is_expired() {
+        let now = Utc::now();
+        assert!(!ban("alice", None).is_expired(now));
+        assert!(!ban("alice", Some(now + TimeDelta::seconds(1))).is_expired(now));
+        assert!(ban("alice", Some(now)).is_expired(now));
+        assert!(ban("alice", Some(now - TimeDelta::seconds(1))).is_expired(now));
+    }
+
+    #[tokio::test]
+    async fn check_should_skip_expired() {
+        let bans = BanList::open(None).await.unwrap();
+        let past = Utc::now() - TimeDelta::seconds(1);
+        bans.add(ban("alice", Some(past))).await.unwrap();
+        bans.add(ban("10.0.0.0/8", Some(past))).await.unwrap();
+        assert!(bans.check(ip("10.0.0.1"), Some("alice")).await.is_none());
+
+        let future = Utc::now() + TimeDelta::hours(1);
+        bans.add(ban("alice", Some(future))).await.unwrap();
+        let hit = bans.check(ip("192.168.0.1"), Some("ALICE")).await.unwrap();
+        assert_eq!(hit.expires_at, Some(future));
+        assert!(bans.check(ip("192.168.0.1"), Some("bob")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn add_should_replace_same_target() {
+        let bans = BanList::open(None).await.unwrap();
+        bans.add(ban("alice", None)).await.unwrap();
+        let future = Utc::now() + TimeDelta::hours(1);
+        bans.add(ban("alice", Some(future))).await.unwrap();
+        let hit = bans.check(ip("10.0.0.1"), Some("alice")).await.unwrap();
+        assert_eq!(hit.expires_at, Some(future));
+        assert!(bans.remove(&"alice".parse().unwrap()).await.unwrap());
+        assert!(bans.check(ip("10.0.0.1"), Some("alice")).await.is_none());
+        assert!(!bans.remove(&"alice".parse().unwrap()).await.unwrap());
+    }
+}
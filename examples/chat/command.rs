@@ -0,0 +1,1641 @@
+use crate::account::Account;
+use crate::ban::{self, BanTarget};
+use crate::config::DuplicateSession;
+use crate::federation;
+use crate::files::{FileStore, Upload as PendingUpload};
+use crate::message::{Event, Message, MessageKind, SystemMessage, SERVER_NAME};
+use crate::state::{Peer, Presence, State};
+use crate::transport::{LineTooLong, Transport};
+use anyhow::{anyhow, Result};
+use chrono::{TimeDelta, Utc};
+use futures::future::BoxFuture;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::{info, warn};
+
+const MAX_NICK_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_TOPIC_LEN: usize = 256;
+const MAX_FILE_NAME_LEN: usize = 255;
+// 一个 reaction 最多几个字符, 组合 emoji 会占好几个
+const MAX_EMOJI_LEN: usize = 8;
+// 下载时每个 Chunk 多少字节, 按行协议 base64 之后大约 16KB 一行
+const DOWNLOAD_CHUNK: usize = 12 * 1024;
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("Unknown command /{0}, try /help")]
+    Unknown(String),
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+    #[error("Invalid nickname: {0}")]
+    InvalidNick(&'static str),
+    #[error("{0}")]
+    Failed(String),
+    // 账号存储之类的内部错误, 详细原因只打日志
+    #[error("Internal server error, please try again later")]
+    Internal(#[from] anyhow::Error),
+}
+
+/// 命令执行完之后连接要怎么处理
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+/// 登录的结果, 游客没有 account
+#[derive(Debug)]
+pub struct Session {
+    pub username: String,
+    pub account: Option<String>,
+}
+
+// 注册过的名字需要再输入一次密码
+enum LoginStep {
+    Done(Session),
+    Password(String),
+}
+
+pub struct Context<'a> {
+    pub state: &'a State,
+    pub registry: &'a Registry,
+    pub addr: SocketAddr,
+    pub peer: &'a mut Peer,
+    // 结构化协议里当前这一帧的 id
+    pub frame_id: Option<u64>,
+}
+
+impl Context<'_> {
+    async fn reply(&self, content: impl Into<String>) {
+        self.state.reply(self.addr, content).await;
+    }
+
+    /// 只有 operator 账号能用的命令, 返回操作人的名字
+    fn require_operator(&self) -> Result<String, CommandError> {
+        if !self.state.is_operator(self.peer.account.as_deref()) {
+            return Err(CommandError::Failed(
+                "Permission denied, only operators can do this".to_string(),
+            ));
+        }
+        Ok(self.peer.username.clone())
+    }
+
+    fn check_muted(&self) -> Result<(), CommandError> {
+        if self.state.is_muted(&self.peer.username) {
+            return Err(CommandError::Failed("You are muted".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 在自己加入的房间里按 id 找消息, id 可以带 `#`
+    fn find_message(&self, id: &str) -> Result<(String, Arc<Message>), CommandError> {
+        let id: u64 = id
+            .trim_start_matches('#')
+            .parse()
+            .map_err(|_| CommandError::Failed(format!("Invalid message id {}", id)))?;
+        self.state
+            .rooms_of(self.addr)
+            .into_iter()
+            .find_map(|room| {
+                let message = self.state.history.find(&room, id)?;
+                Some((room, message))
+            })
+            .filter(|(_, message)| message.is_targetable())
+            .ok_or_else(|| CommandError::Failed(format!("No such message #{} in your rooms", id)))
+    }
+
+    /// 只有发消息的人和 operator 能改, 返回操作人的名字
+    fn require_author(&self, message: &Message, action: &str) -> Result<String, CommandError> {
+        let by = self.peer.username.clone();
+        if federation::is_remote(message) {
+            return Err(CommandError::Failed(format!(
+                "You can not {} messages from other servers",
+                action
+            )));
+        }
+        // 按发消息时的账号或者连接判断, 不看名字, 名字可以被别人 /nick 成一样的
+        if self.peer.wrote(message) {
+            return Ok(by);
+        }
+        self.require_operator().map_err(|_| {
+            CommandError::Failed(format!("You can only {} your own messages", action))
+        })?;
+        info!(
+            target: "audit",
+            action = action,
+            operator = %by,
+            subject = %message.sender,
+            message = message.id
+        );
+        Ok(by)
+    }
+
+    fn files(&self) -> Result<&FileStore, CommandError> {
+        self.state
+            .files
+            .as_ref()
+            .ok_or_else(|| CommandError::Failed("File transfer is disabled".to_string()))
+    }
+
+    fn current_room(&self) -> Result<String, CommandError> {
+        self.peer.room.clone().ok_or_else(|| {
+            CommandError::Failed("You are not in any room, /join <room> first".into())
+        })
+    }
+}
+
+pub trait Command: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn usage(&self) -> &'static str;
+    fn about(&self) -> &'static str;
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>>;
+}
+
+/// 以 `/` 开头的输入都会交给 registry 处理, 新命令通过 `register` 加进来
+pub struct Registry {
+    commands: BTreeMap<&'static str, Box<dyn Command>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register(Nick)
+            .register(Register)
+            .register(Login)
+            .register(Join)
+            .register(Leave)
+            .register(Who)
+            .register(Away)
+            .register(Back)
+            .register(List)
+            .register(Me)
+            .register(Msg)
+            .register(Reply)
+            .register(Edit)
+            .register(Delete)
+            .register(React)
+            .register(Unreact)
+            .register(Upload)
+            .register(Download)
+            .register(History)
+            .register(Topic)
+            .register(Kick)
+            .register(Ban)
+            .register(Unban)
+            .register(Mute)
+            .register(Unmute)
+            .register(Quit)
+            .register(Help);
+        registry
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, command: impl Command + 'static) -> &mut Self {
+        self.commands.insert(command.name(), Box::new(command));
+        self
+    }
+
+    /// 不是命令时返回 None, `//` 开头的转义成普通消息
+    pub fn parse(line: &str) -> Option<(&str, &str)> {
+        let line = line.strip_prefix('/')?;
+        if line.starts_with('/') {
+            return None;
+        }
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        Some((name, args.trim()))
+    }
+
+    /// 返回 None 表示这一行不是命令, 命令出错时只回复给发送者
+    pub async fn dispatch(&self, ctx: &mut Context<'_>, line: &str) -> Option<Flow> {
+        let (name, args) = Self::parse(line)?;
+        let result = match self.commands.get(name) {
+            Some(command) => command.run(ctx, args).await,
+            None => Err(CommandError::Unknown(name.to_string())),
+        };
+        match result {
+            Ok(flow) => Some(flow),
+            Err(e) => {
+                if let CommandError::Internal(e) = &e {
+                    warn!("Command /{} from {} failed: {:#}", name, ctx.addr, e);
+                }
+                ctx.state.error(ctx.addr, ctx.frame_id, e.to_string()).await;
+                Some(Flow::Continue)
+            }
+        }
+    }
+
+    /// 连接上来的第一行就是用户名, 也可以用 `/nick <name>` 或者 `/login <name> <password>`,
+    /// 注册过的名字会再要求输入密码
+    pub async fn login(
+        &self,
+        state: &State,
+        addr: SocketAddr,
+        transport: &mut Transport,
+    ) -> Result<Session> {
+        let mut pending: Option<String> = None;
+        loop {
+            let prompt = match &pending {
+                Some(username) => format!("Password for {}:", username),
+                None => "Enter your username:".to_string(),
+            };
+            transport.send(Event::Prompt(prompt)).await?;
+            let inbound = match transport.next().await {
+                Ok(inbound) => inbound,
+                Err(e) => {
+                    let too_long = e.downcast::<LineTooLong>()?;
+                    let id = too_long.id;
+                    let reason = too_long.to_string();
+                    transport.send(Event::Error { id, reason }).await?;
+                    if let Some(id) = id {
+                        transport.send(Event::Ack(id)).await?;
+                    }
+                    continue;
+                }
+            };
+            let result = match pending.take() {
+                Some(username) => authenticate(state, addr, &username, inbound.line.trim())
+                    .await
+                    .map(LoginStep::Done),
+                None => match Self::parse(&inbound.line) {
+                    Some(("quit", _)) => return Err(anyhow!("Client quit before login")),
+                    Some(("login", args)) => match split_credentials(args) {
+                        Some((username, password)) => authenticate(state, addr, username, password)
+                            .await
+                            .map(LoginStep::Done),
+                        None => Err(CommandError::Usage(Login.usage())),
+                    },
+                    Some(("nick", args)) => login_step(state, args).await,
+                    Some(_) => Err(CommandError::Failed(
+                        "Please choose a username first".to_string(),
+                    )),
+                    None => login_step(state, inbound.line.trim()).await,
+                },
+            };
+            let session = match result {
+                Ok(LoginStep::Done(session)) => Some(session),
+                Ok(LoginStep::Password(username)) => {
+                    pending = Some(username);
+                    None
+                }
+                Err(e) => {
+                    if let CommandError::Internal(e) = &e {
+                        warn!("Login from {} failed: {:#}", addr, e);
+                    }
+                    let reason = e.to_string();
+                    transport
+                        .send(Event::Error {
+                            id: inbound.id,
+                            reason,
+                        })
+                        .await?;
+                    None
+                }
+            };
+            if let Some(id) = inbound.id {
+                transport.send(Event::Ack(id)).await?;
+            }
+            if let Some(session) = session {
+                return Ok(session);
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&dyn Command> {
+        self.commands.get(name).map(|c| c.as_ref())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &dyn Command> {
+        self.commands.values().map(|c| c.as_ref())
+    }
+}
+
+/// 用户名规则: 连接时和 /nick 都走这里
+pub fn validate_nick(name: &str) -> Result<(), CommandError> {
+    if name.is_empty() {
+        return Err(CommandError::InvalidNick("must not be empty"));
+    }
+    if name.chars().count() > MAX_NICK_LEN {
+        return Err(CommandError::InvalidNick("at most 32 characters"));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(CommandError::InvalidNick(
+            "only letters, digits, '_' and '-' are allowed",
+        ));
+    }
+    if name.eq_ignore_ascii_case(SERVER_NAME) {
+        return Err(CommandError::InvalidNick("name is reserved"));
+    }
+    Ok(())
+}
+
+/// 除了格式, 还要检查保留名字, 封禁和别人注册过的名字, account 是当前登录的账号
+async fn check_nick(
+    state: &State,
+    addr: SocketAddr,
+    name: &str,
+    account: Option<&str>,
+) -> Result<(), CommandError> {
+    validate_nick(name)?;
+    if state.config().account.is_reserved(name) {
+        return Err(CommandError::InvalidNick("name is reserved"));
+    }
+    if let Some(ban) = state.bans.check(addr.ip(), Some(name)).await {
+        return Err(CommandError::Failed(ban.describe()));
+    }
+    if account.is_some_and(|account| account.eq_ignore_ascii_case(name)) {
+        return Ok(());
+    }
+    if state.accounts.get(name).await?.is_some() {
+        return Err(CommandError::Failed(format!(
+            "{} is registered, use /login {} <password>",
+            name, name
+        )));
+    }
+    Ok(())
+}
+
+async fn login_step(state: &State, name: &str) -> Result<LoginStep, CommandError> {
+    validate_nick(name)?;
+    if state.config().account.is_reserved(name) {
+        return Err(CommandError::InvalidNick("name is reserved"));
+    }
+    if state.accounts.get(name).await?.is_some() {
+        return Ok(LoginStep::Password(name.to_string()));
+    }
+    Ok(LoginStep::Done(Session {
+        username: name.to_string(),
+        account: None,
+    }))
+}
+
+/// 校验密码和封禁, 同一个账号已经在别的地址登录时按配置拒绝或者踢掉旧的连接
+async fn authenticate(
+    state: &State,
+    addr: SocketAddr,
+    username: &str,
+    password: &str,
+) -> Result<Session, CommandError> {
+    if let Some(ban) = state.bans.check(addr.ip(), Some(username)).await {
+        return Err(CommandError::Failed(ban.describe()));
+    }
+    let account = match state.accounts.get(username).await? {
+        Some(account) if account.verify(password).await? => account,
+        // 不告诉对方是名字不存在还是密码错了
+        _ => {
+            warn!("Failed login for {} from {}", username, addr);
+            return Err(CommandError::Failed(
+                "Invalid username or password".to_string(),
+            ));
+        }
+    };
+    let others: Vec<SocketAddr> = state
+        .sessions_of(&account.username)
+        .into_iter()
+        .filter(|other| *other != addr)
+        .collect();
+    if !others.is_empty() {
+        match state.config().account.duplicate_session {
+            DuplicateSession::Reject => {
+                return Err(CommandError::Failed(format!(
+                    "{} is already connected from another address",
+                    account.username
+                )));
+            }
+            DuplicateSession::TakeOver => {
+                for other in others {
+                    info!(
+                        "{} takes over session {} of {}",
+                        addr, other, account.username
+                    );
+                    state.disconnect(other, "You logged in from another location");
+                }
+            }
+        }
+    }
+    info!("{} logged in as {}", addr, account.username);
+    Ok(Session {
+        username: account.username.clone(),
+        account: Some(account.username),
+    })
+}
+
+/// 拆出第一个参数和剩下的部分, 中间可以是任意空白, 剩下的为空时返回 None
+fn split_arg(args: &str) -> Option<(&str, &str)> {
+    let (first, rest) = args.split_once(char::is_whitespace)?;
+    let rest = rest.trim_start();
+    if rest.is_empty() {
+        return None;
+    }
+    Some((first, rest))
+}
+
+fn split_credentials(args: &str) -> Option<(&str, &str)> {
+    split_arg(args).map(|(username, password)| (username, password.trim_end()))
+}
+
+/// `<target> [rest]`, rest 为空时返回 None
+fn split_target(args: &str) -> Option<(&str, Option<&str>)> {
+    if args.is_empty() {
+        return None;
+    }
+    match args.split_once(char::is_whitespace) {
+        Some((target, rest)) => Some((target, Some(rest.trim()).filter(|r| !r.is_empty()))),
+        None => Some((args, None)),
+    }
+}
+
+/// `30s`, `10m`, `2h`, `7d`
+fn parse_duration(s: &str) -> Option<TimeDelta> {
+    let unit = s.chars().last()?;
+    let n: i64 = s[..s.len() - unit.len_utf8()].parse().ok()?;
+    match unit {
+        's' => TimeDelta::try_seconds(n),
+        'm' => TimeDelta::try_minutes(n),
+        'h' => TimeDelta::try_hours(n),
+        'd' => TimeDelta::try_days(n),
+        _ => None,
+    }
+    .filter(|d| *d > TimeDelta::zero())
+}
+
+/// `/ban` 的参数: target, 可选的时长和原因
+fn parse_ban(args: &str) -> Option<(BanTarget, Option<TimeDelta>, Option<&str>)> {
+    let (target, rest) = split_target(args)?;
+    let target = target.parse().ok()?;
+    // 第一个参数能解析成时长就是时长, 否则都是原因
+    match rest.and_then(split_target) {
+        Some((first, reason)) if parse_duration(first).is_some() => {
+            Some((target, parse_duration(first), reason))
+        }
+        _ => Some((target, None, rest)),
+    }
+}
+
+fn validate_room(room: &str, usage: &'static str) -> Result<(), CommandError> {
+    if room.is_empty() || room.contains(char::is_whitespace) {
+        return Err(CommandError::Usage(usage));
+    }
+    Ok(())
+}
+
+struct Nick;
+
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+    fn usage(&self) -> &'static str {
+        "/nick <name>"
+    }
+    fn about(&self) -> &'static str {
+        "change your username"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            if args.is_empty() {
+                return Err(CommandError::Usage(self.usage()));
+            }
+            check_nick(ctx.state, ctx.addr, args, ctx.peer.account.as_deref()).await?;
+            let from = std::mem::replace(&mut ctx.peer.username, args.to_string());
+            ctx.state
+                .broadcast_system(SystemMessage::Rename {
+                    addr: ctx.addr,
+                    from,
+                    to: args.to_string(),
+                })
+                .await;
+            ctx.reply(format!("You are now known as {}", args)).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Register;
+
+impl Command for Register {
+    fn name(&self) -> &'static str {
+        "register"
+    }
+    fn usage(&self) -> &'static str {
+        "/register <password>"
+    }
+    fn about(&self) -> &'static str {
+        "register your current username with a password"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            if args.is_empty() {
+                return Err(CommandError::Usage(self.usage()));
+            }
+            if let Some(account) = &ctx.peer.account {
+                return Err(CommandError::Failed(format!(
+                    "You are already logged in as {}",
+                    account
+                )));
+            }
+            if args.chars().count() < MIN_PASSWORD_LEN {
+                return Err(CommandError::Failed(format!(
+                    "Password must be at least {} characters",
+                    MIN_PASSWORD_LEN
+                )));
+            }
+            let username = ctx.peer.username.clone();
+            if ctx.state.config().account.is_reserved(&username) {
+                return Err(CommandError::InvalidNick("name is reserved"));
+            }
+            let account = Account::new(&username, args).await?;
+            // 还有别人在用这个名字时不能注册, hash 比较慢, 算完再查
+            if ctx
+                .state
+                .find_user(&username)
+                .iter()
+                .any(|a| *a != ctx.addr)
+            {
+                return Err(CommandError::Failed(format!(
+                    "{} is in use by someone else, pick another name with /nick",
+                    username
+                )));
+            }
+            if !ctx.state.accounts.create(account).await? {
+                return Err(CommandError::Failed(format!(
+                    "{} is already registered",
+                    username
+                )));
+            }
+            info!("{} registered {}", ctx.addr, username);
+            ctx.peer.account = Some(username.clone());
+            ctx.state.set_account(ctx.addr, Some(username.clone()));
+            ctx.reply(format!(
+                "Registered {}, use /login {} <password> next time",
+                username, username
+            ))
+            .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Login;
+
+impl Command for Login {
+    fn name(&self) -> &'static str {
+        "login"
+    }
+    fn usage(&self) -> &'static str {
+        "/login <name> <password>"
+    }
+    fn about(&self) -> &'static str {
+        "log in to a registered account"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let (username, password) =
+                split_credentials(args).ok_or(CommandError::Usage(self.usage()))?;
+            let session = authenticate(ctx.state, ctx.addr, username, password).await?;
+            ctx.peer.account = session.account.clone();
+            ctx.state.set_account(ctx.addr, session.account);
+            if ctx.peer.username != session.username {
+                let from = std::mem::replace(&mut ctx.peer.username, session.username.clone());
+                ctx.state
+                    .broadcast_system(SystemMessage::Rename {
+                        addr: ctx.addr,
+                        from,
+                        to: session.username.clone(),
+                    })
+                    .await;
+            }
+            ctx.reply(format!("You are now logged in as {}", session.username))
+                .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Join;
+
+impl Command for Join {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+    fn usage(&self) -> &'static str {
+        "/join <room>"
+    }
+    fn about(&self) -> &'static str {
+        "join a room and make it your current room"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            validate_room(args, self.usage())?;
+            ctx.state
+                .broadcast_system(SystemMessage::UserJoin {
+                    addr: ctx.addr,
+                    room: args.to_string(),
+                    username: ctx.peer.username.clone(),
+                })
+                .await;
+            ctx.peer.room = Some(args.to_string());
+            ctx.reply(format!("You are now talking in {}", args)).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Leave;
+
+impl Command for Leave {
+    fn name(&self) -> &'static str {
+        "leave"
+    }
+    fn usage(&self) -> &'static str {
+        "/leave [room]"
+    }
+    fn about(&self) -> &'static str {
+        "leave a room, defaults to the current room"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let room = match args {
+                "" => ctx.current_room()?,
+                room => {
+                    validate_room(room, self.usage())?;
+                    room.to_string()
+                }
+            };
+            if !ctx.state.rooms_of(ctx.addr).contains(&room) {
+                return Err(CommandError::Failed(format!(
+                    "You are not in room {}",
+                    room
+                )));
+            }
+            ctx.state
+                .broadcast_system(SystemMessage::UserLeave {
+                    addr: ctx.addr,
+                    room: room.clone(),
+                    username: ctx.peer.username.clone(),
+                })
+                .await;
+            ctx.reply(format!("You left room {}", room)).await;
+            if ctx.peer.room.as_ref() == Some(&room) {
+                ctx.peer.room = ctx.state.rooms_of(ctx.addr).into_iter().next();
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Who;
+
+impl Command for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+    fn usage(&self) -> &'static str {
+        "/who [room]"
+    }
+    fn about(&self) -> &'static str {
+        "list users in a room, defaults to the current room"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let room = match args {
+                "" => ctx.current_room()?,
+                room => room.to_string(),
+            };
+            let members = ctx
+                .state
+                .members(&room)
+                .ok_or_else(|| CommandError::Failed(format!("No such room {}", room)))?;
+            // online 的不标, 其他的在名字后面带上状态
+            let members: Vec<String> = members
+                .into_iter()
+                .map(|(username, presence)| match presence {
+                    Presence::Online => username,
+                    presence => format!("{} ({})", username, presence),
+                })
+                .collect();
+            ctx.reply(format!("Users in {}: {}", room, members.join(", ")))
+                .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Away;
+
+impl Command for Away {
+    fn name(&self) -> &'static str {
+        "away"
+    }
+    fn usage(&self) -> &'static str {
+        "/away [message]"
+    }
+    fn about(&self) -> &'static str {
+        "mark yourself as away until /back"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let message = Some(args.to_string()).filter(|m| !m.is_empty());
+            ctx.state
+                .set_presence(ctx.addr, Presence::Away(message))
+                .await;
+            ctx.reply("You are now away, /back when you return").await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Back;
+
+impl Command for Back {
+    fn name(&self) -> &'static str {
+        "back"
+    }
+    fn usage(&self) -> &'static str {
+        "/back"
+    }
+    fn about(&self) -> &'static str {
+        "clear your away status"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        _args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            if !matches!(ctx.state.presence(ctx.addr), Some(Presence::Away(_))) {
+                return Err(CommandError::Failed("You are not away".to_string()));
+            }
+            ctx.state.set_presence(ctx.addr, Presence::Online).await;
+            ctx.reply("Welcome back").await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct List;
+
+impl Command for List {
+    fn name(&self) -> &'static str {
+        "list"
+    }
+    fn usage(&self) -> &'static str {
+        "/list"
+    }
+    fn about(&self) -> &'static str {
+        "list all rooms"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        _args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let rooms: Vec<String> = ctx
+                .state
+                .room_list()
+                .into_iter()
+                .map(|(room, count)| format!("{} ({})", room, count))
+                .collect();
+            ctx.reply(format!("Rooms: {}", rooms.join(", "))).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Me;
+
+impl Command for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+    fn usage(&self) -> &'static str {
+        "/me <action>"
+    }
+    fn about(&self) -> &'static str {
+        "send an action to the current room"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            if args.is_empty() {
+                return Err(CommandError::Usage(self.usage()));
+            }
+            ctx.check_muted()?;
+            let room = ctx.current_room()?;
+            let message = Message::action(&room, &ctx.peer.username, args).by(ctx.peer.author());
+            ctx.state.broadcast(&room, message).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Msg;
+
+impl Command for Msg {
+    fn name(&self) -> &'static str {
+        "msg"
+    }
+    fn usage(&self) -> &'static str {
+        "/msg <user> <message>"
+    }
+    fn about(&self) -> &'static str {
+        "send a private message to one user"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let (username, content) = split_arg(args).ok_or(CommandError::Usage(self.usage()))?;
+            ctx.check_muted()?;
+            let to = match ctx.state.find_user(username).as_slice() {
+                [] => return Err(CommandError::Failed(format!("{} is not online", username))),
+                [to] => *to,
+                addrs => {
+                    return Err(CommandError::Failed(format!(
+                        "{} is ambiguous, {} users have that name",
+                        username,
+                        addrs.len()
+                    )))
+                }
+            };
+            let message = Message::private(&ctx.peer.username, content);
+            ctx.state.send_message(to, message).await;
+            ctx.reply(format!("-> {}: {}", username, content)).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct History;
+
+impl Command for History {
+    fn name(&self) -> &'static str {
+        "history"
+    }
+    fn usage(&self) -> &'static str {
+        "/history [n]"
+    }
+    fn about(&self) -> &'static str {
+        "show the last n messages of the current room"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let config = ctx.state.history.config();
+            let n = match args {
+                "" => config.replay,
+                n => n.parse().map_err(|_| CommandError::Usage(self.usage()))?,
+            };
+            let room = ctx.current_room()?;
+            let messages = ctx.state.history.recent(&room, n.min(config.capacity));
+            if messages.is_empty() {
+                ctx.reply(format!("No history in {}", room)).await;
+                return Ok(Flow::Continue);
+            }
+            ctx.state.replay(ctx.addr, messages).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Topic;
+
+impl Command for Topic {
+    fn name(&self) -> &'static str {
+        "topic"
+    }
+    fn usage(&self) -> &'static str {
+        "/topic [text]"
+    }
+    fn about(&self) -> &'static str {
+        "show the topic of the current room, operators can change it"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let room = ctx.current_room()?;
+            if args.is_empty() {
+                match ctx.state.topic(&room) {
+                    Some(topic) => ctx.reply(format!("Topic for {}: {}", room, topic)).await,
+                    None => ctx.reply(format!("No topic set for {}", room)).await,
+                }
+                return Ok(Flow::Continue);
+            }
+            let by = ctx.require_operator()?;
+            if args.chars().count() > MAX_TOPIC_LEN {
+                return Err(CommandError::Failed(format!(
+                    "Topic is too long, at most {} characters",
+                    MAX_TOPIC_LEN
+                )));
+            }
+            info!(
+                target: "audit",
+                action = "topic",
+                operator = %by,
+                room = %room,
+                topic = %args
+            );
+            ctx.state
+                .broadcast_system(SystemMessage::Topic {
+                    room,
+                    by,
+                    topic: args.to_string(),
+                })
+                .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Kick;
+
+impl Command for Kick {
+    fn name(&self) -> &'static str {
+        "kick"
+    }
+    fn usage(&self) -> &'static str {
+        "/kick <user> [reason]"
+    }
+    fn about(&self) -> &'static str {
+        "disconnect a user (operators only)"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let by = ctx.require_operator()?;
+            let (username, reason) = split_target(args).ok_or(CommandError::Usage(self.usage()))?;
+            // 重名的连接一起踢掉
+            let peers = ctx
+                .state
+                .select_peers(|_, peer| peer.username.eq_ignore_ascii_case(username));
+            if peers.is_empty() {
+                return Err(CommandError::Failed(format!("{} is not online", username)));
+            }
+            info!(
+                target: "audit",
+                action = "kick",
+                operator = %by,
+                subject = %username,
+                reason = reason.unwrap_or_default()
+            );
+            for (addr, username) in peers {
+                ctx.state
+                    .broadcast_system(SystemMessage::Kick {
+                        addr,
+                        username,
+                        by: by.clone(),
+                        reason: reason.map(String::from),
+                    })
+                    .await;
+            }
+            ctx.reply(format!("Kicked {}", username)).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Ban;
+
+impl Command for Ban {
+    fn name(&self) -> &'static str {
+        "ban"
+    }
+    fn usage(&self) -> &'static str {
+        "/ban <user|ip|cidr> [30s|10m|2h|7d] [reason]"
+    }
+    fn about(&self) -> &'static str {
+        "ban a user or an address, optionally for a limited time (operators only)"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let by = ctx.require_operator()?;
+            let (target, duration, reason) =
+                parse_ban(args).ok_or(CommandError::Usage(self.usage()))?;
+            let now = Utc::now();
+            let ban = ban::Ban {
+                target,
+                by: by.clone(),
+                reason: reason.map(String::from),
+                created_at: now,
+                expires_at: duration.map(|duration| now + duration),
+            };
+            ctx.state.bans.add(ban.clone()).await?;
+            info!(
+                target: "audit",
+                action = "ban",
+                operator = %by,
+                subject = %ban.target,
+                expires_at = ?ban.expires_at,
+                reason = ban.reason.as_deref().unwrap_or_default()
+            );
+            // 不会把自己也断开, 比如 ban 了自己所在的网段
+            let peers = ctx.state.select_peers(|addr, peer| {
+                addr != ctx.addr
+                    && (ban.target.matches(addr.ip(), Some(&peer.username))
+                        || ban.target.matches(addr.ip(), peer.account.as_deref()))
+            });
+            let count = peers.len();
+            for (addr, username) in peers {
+                ctx.state
+                    .broadcast_system(SystemMessage::Ban {
+                        addr,
+                        username,
+                        ban: ban.clone(),
+                    })
+                    .await;
+            }
+            ctx.reply(format!(
+                "Banned {}{}, {} connections closed",
+                ban.target,
+                ban::until(ban.expires_at),
+                count
+            ))
+            .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Unban;
+
+impl Command for Unban {
+    fn name(&self) -> &'static str {
+        "unban"
+    }
+    fn usage(&self) -> &'static str {
+        "/unban <user|ip|cidr>"
+    }
+    fn about(&self) -> &'static str {
+        "lift a ban (operators only)"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let by = ctx.require_operator()?;
+            let target: BanTarget = args
+                .parse()
+                .map_err(|_| CommandError::Usage(self.usage()))?;
+            if !ctx.state.bans.remove(&target).await? {
+                return Err(CommandError::Failed(format!("{} is not banned", target)));
+            }
+            info!(
+                target: "audit",
+                action = "unban",
+                operator = %by,
+                subject = %target
+            );
+            ctx.reply(format!("Unbanned {}", target)).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Mute;
+
+impl Command for Mute {
+    fn name(&self) -> &'static str {
+        "mute"
+    }
+    fn usage(&self) -> &'static str {
+        "/mute <user> [30s|10m|2h|7d]"
+    }
+    fn about(&self) -> &'static str {
+        "stop a user from talking, optionally for a limited time (operators only)"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let by = ctx.require_operator()?;
+            let (username, duration) = match split_target(args) {
+                Some((username, None)) => (username, None),
+                Some((username, Some(duration))) => match parse_duration(duration) {
+                    Some(duration) => (username, Some(duration)),
+                    None => return Err(CommandError::Usage(self.usage())),
+                },
+                None => return Err(CommandError::Usage(self.usage())),
+            };
+            validate_nick(username)?;
+            let expires_at = duration.map(|duration| Utc::now() + duration);
+            info!(
+                target: "audit",
+                action = "mute",
+                operator = %by,
+                subject = %username,
+                expires_at = ?expires_at
+            );
+            ctx.state
+                .broadcast_system(SystemMessage::Mute {
+                    username: username.to_string(),
+                    by,
+                    expires_at,
+                })
+                .await;
+            ctx.reply(format!("Muted {}{}", username, ban::until(expires_at)))
+                .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Unmute;
+
+impl Command for Unmute {
+    fn name(&self) -> &'static str {
+        "unmute"
+    }
+    fn usage(&self) -> &'static str {
+        "/unmute <user>"
+    }
+    fn about(&self) -> &'static str {
+        "let a muted user talk again (operators only)"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let by = ctx.require_operator()?;
+            if args.is_empty() {
+                return Err(CommandError::Usage(self.usage()));
+            }
+            if !ctx.state.is_muted(args) {
+                return Err(CommandError::Failed(format!("{} is not muted", args)));
+            }
+            info!(
+                target: "audit",
+                action = "unmute",
+                operator = %by,
+                subject = %args
+            );
+            ctx.state
+                .broadcast_system(SystemMessage::Unmute {
+                    username: args.to_string(),
+                    by,
+                })
+                .await;
+            ctx.reply(format!("Unmuted {}", args)).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Quit;
+
+impl Command for Quit {
+    fn name(&self) -> &'static str {
+        "quit"
+    }
+    fn usage(&self) -> &'static str {
+        "/quit"
+    }
+    fn about(&self) -> &'static str {
+        "disconnect from the server"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        _args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            ctx.reply("Bye!").await;
+            Ok(Flow::Quit)
+        })
+    }
+}
+
+struct Reply;
+
+impl Command for Reply {
+    fn name(&self) -> &'static str {
+        "reply"
+    }
+    fn usage(&self) -> &'static str {
+        "/reply <id> <message>"
+    }
+    fn about(&self) -> &'static str {
+        "reply to a message in its room"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let (id, content) = split_arg(args).ok_or(CommandError::Usage(self.usage()))?;
+            ctx.check_muted()?;
+            let (room, parent) = ctx.find_message(id)?;
+            let message =
+                Message::chat(&room, &ctx.peer.username, content.trim()).by(ctx.peer.author());
+            ctx.state
+                .broadcast(&room, message.in_reply_to(parent.id))
+                .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Edit;
+
+impl Command for Edit {
+    fn name(&self) -> &'static str {
+        "edit"
+    }
+    fn usage(&self) -> &'static str {
+        "/edit <id> <message>"
+    }
+    fn about(&self) -> &'static str {
+        "change one of your messages"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let (id, content) = split_arg(args).ok_or(CommandError::Usage(self.usage()))?;
+            ctx.check_muted()?;
+            let (room, message) = ctx.find_message(id)?;
+            // 文件只能删不能改
+            if message.kind == MessageKind::File {
+                return Err(CommandError::Failed(
+                    "Shared files can not be edited".into(),
+                ));
+            }
+            let by = ctx.require_author(&message, "edit")?;
+            let edit = Message::edit(&room, by, message.id, content.trim());
+            ctx.state.broadcast(&room, edit).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Delete;
+
+impl Command for Delete {
+    fn name(&self) -> &'static str {
+        "delete"
+    }
+    fn usage(&self) -> &'static str {
+        "/delete <id>"
+    }
+    fn about(&self) -> &'static str {
+        "delete one of your messages"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            if args.is_empty() || args.contains(' ') {
+                return Err(CommandError::Usage(self.usage()));
+            }
+            let (room, message) = ctx.find_message(args)?;
+            let by = ctx.require_author(&message, "delete")?;
+            ctx.state
+                .broadcast(&room, Message::delete(&room, by, message.id))
+                .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// 只接受 emoji, 不能拿来发文字
+fn parse_emoji(emoji: &str) -> Result<&str, CommandError> {
+    let len = emoji.chars().count();
+    if len == 0 || len > MAX_EMOJI_LEN || emoji.chars().any(|c| c.is_ascii() || c.is_whitespace()) {
+        return Err(CommandError::Failed(format!("Invalid emoji {}", emoji)));
+    }
+    Ok(emoji)
+}
+
+struct React;
+
+impl Command for React {
+    fn name(&self) -> &'static str {
+        "react"
+    }
+    fn usage(&self) -> &'static str {
+        "/react <id> <emoji>"
+    }
+    fn about(&self) -> &'static str {
+        "react to a message with an emoji"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let (id, emoji) = split_arg(args).ok_or(CommandError::Usage(self.usage()))?;
+            let emoji = parse_emoji(emoji)?;
+            ctx.check_muted()?;
+            let (room, message) = ctx.find_message(id)?;
+            let react = Message::react(&room, &ctx.peer.username, message.id, emoji);
+            ctx.state.broadcast(&room, react).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Unreact;
+
+impl Command for Unreact {
+    fn name(&self) -> &'static str {
+        "unreact"
+    }
+    fn usage(&self) -> &'static str {
+        "/unreact <id> <emoji>"
+    }
+    fn about(&self) -> &'static str {
+        "remove your reaction from a message"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let (id, emoji) = split_arg(args).ok_or(CommandError::Usage(self.usage()))?;
+            let emoji = parse_emoji(emoji)?;
+            let (room, message) = ctx.find_message(id)?;
+            let unreact = Message::unreact(&room, &ctx.peer.username, message.id, emoji);
+            ctx.state.broadcast(&room, unreact).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Upload;
+
+impl Command for Upload {
+    fn name(&self) -> &'static str {
+        "upload"
+    }
+    fn usage(&self) -> &'static str {
+        "/upload <size> <name>"
+    }
+    fn about(&self) -> &'static str {
+        "share a file in the current room, then send it with /chunk <base64>"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            let (size, name) = split_arg(args)
+                .and_then(|(size, name)| Some((size.parse::<u64>().ok()?, name)))
+                .ok_or(CommandError::Usage(self.usage()))?;
+            // 只要文件名, 不要路径
+            let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+            if name.is_empty() || name.len() > MAX_FILE_NAME_LEN {
+                return Err(CommandError::Failed(format!(
+                    "File name must be 1 to {} bytes",
+                    MAX_FILE_NAME_LEN
+                )));
+            }
+            let max_size = ctx.files()?.max_size();
+            if size == 0 || size > max_size {
+                return Err(CommandError::Failed(format!(
+                    "File size must be 1 to {} bytes",
+                    max_size
+                )));
+            }
+            ctx.check_muted()?;
+            let room = ctx.current_room()?;
+            if let Some(upload) = ctx.peer.upload.take() {
+                ctx.reply(format!("Upload of {} cancelled", upload.name))
+                    .await;
+            }
+            ctx.peer.upload = Some(PendingUpload::new(name.to_string(), size, room));
+            ctx.reply(format!("Ready to receive {} ({} bytes)", name, size))
+                .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Download;
+
+impl Command for Download {
+    fn name(&self) -> &'static str {
+        "download"
+    }
+    fn usage(&self) -> &'static str {
+        "/download <hash>"
+    }
+    fn about(&self) -> &'static str {
+        "fetch a shared file"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            if args.is_empty() || args.contains(' ') {
+                return Err(CommandError::Usage(self.usage()));
+            }
+            let (name, data) = ctx
+                .files()?
+                .get(args)
+                .await?
+                .ok_or_else(|| CommandError::Failed(format!("No such file {}", args)))?;
+            let header = Event::File {
+                hash: args.to_lowercase(),
+                name,
+                size: data.len() as u64,
+            };
+            let chunks = (0..data.len()).step_by(DOWNLOAD_CHUNK).map(|start| {
+                Event::Chunk(data.slice(start..data.len().min(start + DOWNLOAD_CHUNK)))
+            });
+            ctx.state
+                .send_all(ctx.addr, std::iter::once(header).chain(chunks))
+                .await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+struct Help;
+
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+    fn usage(&self) -> &'static str {
+        "/help [command]"
+    }
+    fn about(&self) -> &'static str {
+        "show available commands"
+    }
+    fn run<'a>(
+        &'a self,
+        ctx: &'a mut Context<'_>,
+        args: &'a str,
+    ) -> BoxFuture<'a, Result<Flow, CommandError>> {
+        Box::pin(async move {
+            if !args.is_empty() {
+                let name = args.trim_start_matches('/');
+                let command = ctx
+                    .registry
+                    .get(name)
+                    .ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+                ctx.reply(format!("{} - {}", command.usage(), command.about()))
+                    .await;
+                return Ok(Flow::Continue);
+            }
+            let lines: Vec<String> = ctx
+                .registry
+                .iter()
+                .map(|command| format!("{} - {}", command.usage(), command.about()))
+                .collect();
+            for line in lines {
+                ctx.reply(line).await;
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_arg_should_accept_any_whitespace() {
+        assert_eq!(split_arg("42 hello"), Some(("42", "hello")));
+        assert_eq!(split_arg("42\t  hello world"), Some(("42", "hello world")));
+        assert_eq!(split_arg("42\u{3000}👍"), Some(("42", "👍")));
+        assert_eq!(split_arg("42"), None);
+        assert_eq!(split_arg("42 \t"), None);
+    }
+
+    #[test]
+    fn parse_duration_should_accept_units() {
+        assert_eq!(parse_duration("30s"), Some(TimeDelta::seconds(30)));
+        assert_eq!(parse_duration("10m"), Some(TimeDelta::minutes(10)));
+        assert_eq!(parse_duration("2h"), Some(TimeDelta::hours(2)));
+        assert_eq!(parse_duration("7d"), Some(TimeDelta::days(7)));
+    }
+
+    #[test]
+    fn parse_duration_should_reject_malformed() {
+        for s in [
+            "",
+            "s",
+            "10",
+            "10x",
+            "1.5h",
+            "-5m",
+            "0s",
+            "h2",
+            "10 m",
+            "99999999999999d",
+            "5秒",
+        ] {
+            assert_eq!(parse_duration(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parse_ban_should_split_target_duration_and_reason() {
+        let (target, duration, reason) = parse_ban("alice").unwrap();
+        assert_eq!(target, BanTarget::User("alice".to_string()));
+        assert_eq!((duration, reason), (None, None));
+
+        let (target, duration, reason) = parse_ban("10.0.0.1 2h  too  many links ").unwrap();
+        assert_eq!(target, "10.0.0.1/32".parse::<BanTarget>().unwrap());
+        assert_eq!(duration, Some(TimeDelta::hours(2)));
+        assert_eq!(reason, Some("too  many links"));
+
+        let (target, duration, reason) = parse_ban("10.1.2.3/16\tspam").unwrap();
+        assert_eq!(target, "10.1.0.0/16".parse::<BanTarget>().unwrap());
+        assert_eq!((duration, reason), (None, Some("spam")));
+    }
+
+    #[test]
+    fn parse_ban_should_treat_bad_duration_as_reason() {
+        let (_, duration, reason) = parse_ban("bob 2x spam").unwrap();
+        assert_eq!((duration, reason), (None, Some("2x spam")));
+        let (_, duration, reason) = parse_ban("bob 7d").unwrap();
+        assert_eq!((duration, reason), (Some(TimeDelta::days(7)), None));
+    }
+
+    #[test]
+    fn parse_ban_should_reject_missing_target() {
+        assert!(parse_ban("").is_none());
+    }
+}
@@ -0,0 +1,493 @@
+use anyhow::{anyhow, Result};
+use chrono::TimeDelta;
+use ecosystem::tls::TlsConfig;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen_addr: SocketAddr,
+    // 结构化协议 (长度 + JSON), None 表示不开
+    pub framed_addr: Option<SocketAddr>,
+    // WebSocket 网关, None 表示不开
+    pub ws_addr: Option<SocketAddr>,
+    // 证书和私钥, None 表示不开 TLS
+    pub tls: Option<TlsConfig>,
+    pub history: HistoryConfig,
+    pub outbox: OutboxConfig,
+    // 聊天记录目录, None 表示不落盘
+    pub transcript_dir: Option<PathBuf>,
+    pub account: AccountConfig,
+    pub limits: LimitConfig,
+    pub moderation: ModerationConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub federation: FederationConfig,
+    pub admin: AdminConfig,
+    pub files: FileConfig,
+    // 退出时最多等多久让客户端收完最后的消息
+    pub shutdown_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    // 每个房间最多保留多少条
+    pub capacity: usize,
+    // 加入房间时回放多少条
+    pub replay: usize,
+    // 超过这个时间的消息不再回放
+    pub max_age: TimeDelta,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    // 每个 peer 最多排队多少条
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+    // 丢消息时多久提醒客户端一次
+    pub warn_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    // 一行最多多少字节, 超过的丢掉并回复错误
+    pub max_line_len: usize,
+    // 结构化协议一帧/WebSocket 一条消息最多多少字节, 超过时只能断开
+    pub max_frame_len: usize,
+    // 令牌桶: 每秒补充多少条, 最多攒多少条
+    pub rate: f64,
+    pub burst: u32,
+    pub penalty: FloodPenalty,
+}
+
+/// 发得太快时怎么办
+#[derive(Debug, Clone, Copy)]
+pub enum FloodPenalty {
+    // 等到有 token 再处理, 相当于限速
+    Throttle,
+    // 丢掉这一行并回复错误
+    Warn,
+    // 直接断开
+    Kick,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    // 这么久没收到任何东西就发一次 ping
+    pub ping_interval: Duration,
+    // 这么久没收到任何东西 (包括 pong) 就断开
+    pub timeout: Duration,
+    // 这么久没说话就显示为 idle
+    pub idle_after: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct FederationConfig {
+    // 这台服务器的名字, 在别的服务器上用户显示为 `user@server_name`
+    pub server_name: String,
+    // 共享密钥, None 表示不开 federation
+    pub secret: Option<String>,
+    // 等别的服务器连进来的地址, None 表示只主动连出去
+    pub listen_addr: Option<SocketAddr>,
+    // 主动连接的服务器, 两边只需要有一边配置
+    pub peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileConfig {
+    // 按 blake3 hash 存文件的目录, None 表示不能传文件
+    pub dir: Option<PathBuf>,
+    // 单个文件最多多少字节
+    pub max_size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    // 默认只听本机, 对外开放时记得配 TLS
+    pub listen_addr: SocketAddr,
+    // 请求要带 `Authorization: Bearer <token>`, None 表示不开 admin API
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModerationConfig {
+    // 这些账号登录后可以用 /kick /ban /mute /topic
+    pub operators: Vec<String>,
+    // None 表示 ban 只在内存里
+    pub ban_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccountConfig {
+    pub store: AccountStoreConfig,
+    // 不能注册也不能当用户名用, 不区分大小写
+    pub reserved: Vec<String>,
+    pub duplicate_session: DuplicateSession,
+}
+
+#[derive(Debug, Clone)]
+pub enum AccountStoreConfig {
+    // JSON 文件
+    File(PathBuf),
+    // postgres://...
+    Postgres(String),
+}
+
+/// 同一个账号在别的地址已经登录时怎么办
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateSession {
+    // 拒绝新的登录
+    Reject,
+    // 断开旧的连接
+    TakeOver,
+}
+
+/// 客户端收得太慢, 队列满了怎么办
+#[derive(Debug, Clone, Copy)]
+pub enum SlowConsumerPolicy {
+    DropOldest,
+    DropNew,
+    // 队列一直满超过这个时间就断开
+    Disconnect(Duration),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            framed_addr: Some(SocketAddr::from(([0, 0, 0, 0], 8082))),
+            ws_addr: Some(SocketAddr::from(([0, 0, 0, 0], 8081))),
+            tls: None,
+            history: HistoryConfig::default(),
+            outbox: OutboxConfig::default(),
+            transcript_dir: Some(PathBuf::from("./tmp/chat")),
+            account: AccountConfig::default(),
+            limits: LimitConfig::default(),
+            moderation: ModerationConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            federation: FederationConfig::default(),
+            admin: AdminConfig::default(),
+            files: FileConfig::default(),
+            shutdown_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 200,
+            replay: 20,
+            max_age: TimeDelta::hours(24),
+        }
+    }
+}
+
+impl Config {
+    /// 在默认配置上用 `CHAT_*` 环境变量覆盖
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        if let Ok(addr) = env::var("CHAT_ADDR") {
+            config.listen_addr = addr.parse()?;
+        }
+        if let Ok(addr) = env::var("CHAT_FRAMED_ADDR") {
+            config.framed_addr = optional_addr(&addr)?;
+        }
+        if let Ok(addr) = env::var("CHAT_WS_ADDR") {
+            config.ws_addr = optional_addr(&addr)?;
+        }
+        config.tls = TlsConfig::from_env("CHAT_TLS")?;
+        if let Ok(dir) = env::var("CHAT_TRANSCRIPT_DIR") {
+            config.transcript_dir = match dir.as_str() {
+                "" | "off" => None,
+                dir => Some(PathBuf::from(dir)),
+            };
+        }
+        if let Ok(secs) = env::var("CHAT_SHUTDOWN_TIMEOUT") {
+            config.shutdown_timeout = Duration::from_secs(secs.parse()?);
+        }
+        if let Ok(capacity) = env::var("CHAT_OUTBOX_CAPACITY") {
+            config.outbox.capacity = capacity.parse()?;
+        }
+        if let Ok(policy) = env::var("CHAT_SLOW_CONSUMER") {
+            config.outbox.policy = policy.parse()?;
+        }
+        if let Ok(len) = env::var("CHAT_MAX_LINE_LEN") {
+            config.limits.max_line_len = len.parse()?;
+        }
+        if let Ok(rate) = env::var("CHAT_RATE_LIMIT") {
+            let (rate, burst) = rate.split_once('/').ok_or_else(|| {
+                anyhow!("invalid rate limit {}, expect <per-second>/<burst>", rate)
+            })?;
+            config.limits.rate = rate.parse()?;
+            config.limits.burst = burst.parse()?;
+            if config.limits.rate <= 0.0 || config.limits.burst == 0 {
+                return Err(anyhow!("rate limit must be positive"));
+            }
+        }
+        if let Ok(penalty) = env::var("CHAT_FLOOD_PENALTY") {
+            config.limits.penalty = penalty.parse()?;
+        }
+        if let Ok(secs) = env::var("CHAT_PING_INTERVAL") {
+            config.heartbeat.ping_interval = Duration::from_secs(secs.parse()?);
+        }
+        if let Ok(secs) = env::var("CHAT_IDLE_TIMEOUT") {
+            config.heartbeat.timeout = Duration::from_secs(secs.parse()?);
+        }
+        if let Ok(secs) = env::var("CHAT_IDLE_AFTER") {
+            config.heartbeat.idle_after = Duration::from_secs(secs.parse()?);
+        }
+        if let Ok(name) = env::var("CHAT_SERVER_NAME") {
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '@') {
+                return Err(anyhow!("invalid server name {}", name));
+            }
+            config.federation.server_name = name;
+        }
+        if let Ok(secret) = env::var("CHAT_LINK_SECRET") {
+            config.federation.secret = Some(secret);
+        }
+        if let Ok(addr) = env::var("CHAT_LINK_ADDR") {
+            config.federation.listen_addr = optional_addr(&addr)?;
+        }
+        if let Ok(peers) = env::var("CHAT_LINK_PEERS") {
+            config.federation.peers = split_list(&peers)
+                .iter()
+                .map(|peer| peer.parse())
+                .collect::<Result<_, _>>()?;
+        }
+        if let Ok(dir) = env::var("CHAT_FILE_DIR") {
+            config.files.dir = match dir.as_str() {
+                "" | "off" => None,
+                dir => Some(PathBuf::from(dir)),
+            };
+        }
+        if let Ok(size) = env::var("CHAT_MAX_FILE_SIZE") {
+            config.files.max_size = size.parse()?;
+        }
+        if let Ok(addr) = env::var("CHAT_ADMIN_ADDR") {
+            config.admin.listen_addr = addr.parse()?;
+        }
+        if let Ok(token) = env::var("CHAT_ADMIN_TOKEN") {
+            config.admin.token = Some(token).filter(|token| !token.is_empty());
+        }
+        if let Ok(operators) = env::var("CHAT_OPERATORS") {
+            config.moderation.operators = split_list(&operators);
+        }
+        if let Ok(path) = env::var("CHAT_BAN_FILE") {
+            config.moderation.ban_file = Some(PathBuf::from(path));
+        }
+        if let Ok(store) = env::var("CHAT_ACCOUNT_STORE") {
+            config.account.store = store.parse()?;
+        }
+        if let Ok(reserved) = env::var("CHAT_RESERVED_NAMES") {
+            config.account.reserved = split_list(&reserved);
+        }
+        if let Ok(duplicate) = env::var("CHAT_DUPLICATE_SESSION") {
+            config.account.duplicate_session = duplicate.parse()?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.outbox.capacity == 0 {
+            return Err(anyhow!("outbox capacity must be positive"));
+        }
+        let limits = &self.limits;
+        // 一行要能放进一帧里, 否则超长的行会直接断开, 而不是回复错误
+        if limits.max_line_len == 0 || limits.max_line_len > limits.max_frame_len {
+            return Err(anyhow!(
+                "max line length must be between 1 and the max frame length {}",
+                limits.max_frame_len
+            ));
+        }
+        if self.heartbeat.ping_interval.is_zero() {
+            return Err(anyhow!("ping interval must be positive"));
+        }
+        Ok(())
+    }
+}
+
+/// 空字符串或者 off 表示不开
+fn optional_addr(s: &str) -> Result<Option<SocketAddr>> {
+    match s {
+        "" | "off" => Ok(None),
+        addr => Ok(Some(addr.parse()?)),
+    }
+}
+
+/// 逗号分隔的名字列表
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// `drop-oldest`, `drop-new` 或者 `disconnect:<秒>`
+impl FromStr for SlowConsumerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "drop-oldest" => Ok(Self::DropOldest),
+            None if s == "drop-new" => Ok(Self::DropNew),
+            Some(("disconnect", secs)) => Ok(Self::Disconnect(Duration::from_secs(secs.parse()?))),
+            _ => Err(anyhow!(
+                "invalid slow consumer policy {}, expect drop-oldest, drop-new or disconnect:<secs>",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            policy: SlowConsumerPolicy::DropOldest,
+            warn_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            max_line_len: 4096,
+            max_frame_len: 16 * 1024,
+            rate: 5.0,
+            burst: 10,
+            penalty: FloodPenalty::Throttle,
+        }
+    }
+}
+
+/// `throttle`, `warn` 或者 `kick`
+impl FromStr for FloodPenalty {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "throttle" => Ok(Self::Throttle),
+            "warn" => Ok(Self::Warn),
+            "kick" => Ok(Self::Kick),
+            _ => Err(anyhow!(
+                "invalid flood penalty {}, expect throttle, warn or kick",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(600),
+            idle_after: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            server_name: "chat".to_string(),
+            secret: None,
+            listen_addr: Some(SocketAddr::from(([0, 0, 0, 0], 8083))),
+            peers: vec![],
+        }
+    }
+}
+
+impl Default for FileConfig {
+    fn default() -> Self {
+        Self {
+            dir: Some(PathBuf::from("./tmp/chat/files")),
+            max_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 8084)),
+            token: None,
+        }
+    }
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            operators: vec![],
+            ban_file: Some(PathBuf::from("./tmp/chat/bans.json")),
+        }
+    }
+}
+
+impl ModerationConfig {
+    pub fn is_operator(&self, account: &str) -> bool {
+        self.operators
+            .iter()
+            .any(|operator| operator.eq_ignore_ascii_case(account))
+    }
+}
+
+impl Default for AccountConfig {
+    fn default() -> Self {
+        Self {
+            store: AccountStoreConfig::File(PathBuf::from("./tmp/chat/accounts.json")),
+            reserved: ["admin", "root", "operator", "moderator"]
+                .map(String::from)
+                .to_vec(),
+            duplicate_session: DuplicateSession::Reject,
+        }
+    }
+}
+
+impl AccountConfig {
+    pub fn is_reserved(&self, name: &str) -> bool {
+        self.reserved
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(name))
+    }
+}
+
+/// `postgres://...` 或者 `file:<path>`
+impl FromStr for AccountStoreConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("postgres://") || s.starts_with("postgresql://") {
+            return Ok(Self::Postgres(s.to_string()));
+        }
+        match s.strip_prefix("file:") {
+            Some(path) if !path.is_empty() => Ok(Self::File(PathBuf::from(path))),
+            _ => Err(anyhow!(
+                "invalid account store {}, expect file:<path> or postgres://...",
+                s
+            )),
+        }
+    }
+}
+
+/// `reject` 或者 `takeover`
+impl FromStr for DuplicateSession {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "takeover" => Ok(Self::TakeOver),
+            _ => Err(anyhow!(
+                "invalid duplicate session policy {}, expect reject or takeover",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use crate::config::FederationConfig;
+use crate::message::Message;
+use crate::state::State;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use ecosystem::chat::{FrameCodec, MessageKind, Payload};
+use futures::{SinkExt, StreamExt};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::Framed;
+use tracing::{debug, info, warn};
+
+const LINK_VERSION: u32 = 1;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// 每条 link 最多排队多少条, 对面太慢时丢掉
+const LINK_QUEUE: usize = 4096;
+const MAX_LINK_FRAME: usize = 1024 * 1024;
+// 记住最近多少条转发过的消息用来去重
+const SEEN_CAPACITY: usize = 16 * 1024;
+// 记住最近多少条别的服务器来的消息在本机的 id
+const IDS_CAPACITY: usize = 16 * 1024;
+
+/// 服务器之间的帧, 握手时双方各发一个 Hello, 再用对方的 nonce 算 Auth
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LinkFrame {
+    Hello {
+        version: u32,
+        server: String,
+        nonce: String,
+    },
+    // keyed_hash(key, 对方的 nonce + 自己的名字)
+    Auth {
+        mac: String,
+    },
+    Relay(Box<Relay>),
+    Error {
+        reason: String,
+    },
+}
+
+// link 上不传文件
+impl Payload for LinkFrame {}
+
+/// 转发的房间消息, (origin, id) 全局唯一, hops 是经过的服务器, 用来防止转圈
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Relay {
+    pub origin: String,
+    pub id: u64,
+    pub hops: Vec<String>,
+    pub message: Message,
+    // 消息 id 每个服务器自己分配, reply_to 和 target 用 (origin, id) 表示, 收到后换成本机的
+    #[serde(default)]
+    pub reply_to: Option<(String, u64)>,
+    #[serde(default)]
+    pub target: Option<(String, u64)>,
+}
+
+/// 当前连着的其他服务器
+#[derive(Debug)]
+pub struct Links {
+    server: String,
+    // None 表示没开 federation
+    key: Option<[u8; 32]>,
+    // server name -> (link id, 发送队列)
+    peers: DashMap<String, (u64, mpsc::Sender<LinkFrame>)>,
+    next_id: AtomicU64,
+    seen: Mutex<Seen>,
+    ids: Mutex<Ids>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    set: HashSet<(String, u64)>,
+    order: VecDeque<(String, u64)>,
+}
+
+/// 别的服务器来的消息: (origin, origin 上的 id) <-> 本机的 id
+#[derive(Debug, Default)]
+struct Ids {
+    local: HashMap<(String, u64), u64>,
+    global: HashMap<u64, (String, u64)>,
+    order: VecDeque<u64>,
+}
+
+impl Links {
+    pub fn new(config: &FederationConfig) -> Self {
+        Self {
+            server: config.server_name.clone(),
+            key: config.secret.as_ref().map(|secret| {
+                blake3::derive_key("ecosystem chat federation v1", secret.as_bytes())
+            }),
+            peers: DashMap::new(),
+            next_id: AtomicU64::new(1),
+            seen: Mutex::new(Seen::default()),
+            ids: Mutex::new(Ids::default()),
+        }
+    }
+
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /// 本地的消息转发给所有连着的服务器, sender 带上服务器的名字
+    pub fn relay(&self, message: &Message) {
+        if self.peers.is_empty() || !is_relayed(message) {
+            return;
+        }
+        self.first_seen(&self.server, message.id);
+        let mut message = message.clone();
+        message.sender = format!("{}@{}", message.sender, self.server);
+        // 账号和连接只在本服务器上有意义
+        message.author = None;
+        let reply_to = message.reply_to.take().map(|id| self.global(id));
+        let target = message.target.take().map(|id| self.global(id));
+        self.forward(Relay {
+            origin: self.server.clone(),
+            id: message.id,
+            hops: vec![self.server.clone()],
+            message,
+            reply_to,
+            target,
+        });
+    }
+
+    /// 本机的 id 换成 (origin, origin 上的 id), 本机发的消息 origin 就是自己
+    fn global(&self, id: u64) -> (String, u64) {
+        let ids = self.ids.lock().unwrap();
+        match ids.global.get(&id) {
+            Some(global) => global.clone(),
+            None => (self.server.clone(), id),
+        }
+    }
+
+    /// 找不到 (太旧了或者没收到过) 时返回 None
+    fn local(&self, (origin, id): &(String, u64)) -> Option<u64> {
+        if *origin == self.server {
+            return Some(*id);
+        }
+        let ids = self.ids.lock().unwrap();
+        ids.local.get(&(origin.clone(), *id)).copied()
+    }
+
+    fn map(&self, origin: &str, id: u64, local: u64) {
+        let mut guard = self.ids.lock().unwrap();
+        let ids = &mut *guard;
+        let global = (origin.to_string(), id);
+        ids.local.insert(global.clone(), local);
+        ids.global.insert(local, global);
+        ids.order.push_back(local);
+        while ids.order.len() > IDS_CAPACITY {
+            if let Some(global) = ids
+                .order
+                .pop_front()
+                .and_then(|old| ids.global.remove(&old))
+            {
+                ids.local.remove(&global);
+            }
+        }
+    }
+
+    /// 发给还没经过的服务器
+    fn forward(&self, relay: Relay) {
+        let peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|peer| !relay.hops.contains(peer.key()))
+            .map(|peer| (peer.key().clone(), peer.value().1.clone()))
+            .collect();
+        for (server, sender) in peers {
+            if sender
+                .try_send(LinkFrame::Relay(Box::new(relay.clone())))
+                .is_err()
+            {
+                warn!("Link to {} is lagging, dropping message", server);
+            }
+        }
+    }
+
+    /// 返回 false 表示已经处理过了
+    fn first_seen(&self, origin: &str, id: u64) -> bool {
+        let key = (origin.to_string(), id);
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.set.insert(key.clone()) {
+            return false;
+        }
+        seen.order.push_back(key);
+        while seen.order.len() > SEEN_CAPACITY {
+            if let Some(old) = seen.order.pop_front() {
+                seen.set.remove(&old);
+            }
+        }
+        true
+    }
+
+    /// 同一个服务器只保留一条 link, 先连上的留下
+    fn add(&self, server: &str, sender: mpsc::Sender<LinkFrame>) -> Option<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        match self.peers.entry(server.to_string()) {
+            dashmap::Entry::Occupied(_) => None,
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert((id, sender));
+                Some(id)
+            }
+        }
+    }
+
+    fn remove(&self, server: &str, id: u64) {
+        self.peers
+            .remove_if(server, |_, (link_id, _)| *link_id == id);
+    }
+
+    fn mac(&self, key: &[u8; 32], nonce: &str, server: &str) -> blake3::Hash {
+        blake3::keyed_hash(key, format!("{}\n{}", nonce, server).as_bytes())
+    }
+}
+
+/// 只转发房间里的聊天, 进出和对消息的编辑, 删除, react; 系统通知各个服务器自己发,
+/// 文件在本机的存储里, 别的服务器下载不了
+fn is_relayed(message: &Message) -> bool {
+    message.room.is_some()
+        && matches!(
+            message.kind,
+            MessageKind::Chat
+                | MessageKind::Action
+                | MessageKind::Join
+                | MessageKind::Leave
+                | MessageKind::Edit
+                | MessageKind::Delete
+                | MessageKind::React
+                | MessageKind::Unreact
+        )
+}
+
+/// 编辑和删除只能在消息所在的服务器上做, 其他的事件谁都可以发
+fn is_changed_by_origin(kind: MessageKind) -> bool {
+    matches!(kind, MessageKind::Edit | MessageKind::Delete)
+}
+
+/// 别的服务器转发来的消息, author 记的是 origin, 本机的人不能改
+pub fn remote_author(origin: &str) -> String {
+    format!("server:{}", origin)
+}
+
+pub fn is_remote(message: &Message) -> bool {
+    message
+        .author
+        .as_ref()
+        .is_some_and(|author| author.starts_with("server:"))
+}
+
+/// 等别的服务器连进来
+pub async fn serve(listener: TcpListener, state: Arc<State>) -> Result<()> {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = state.closing().cancelled() => return Ok(()),
+        };
+        info!("Got link connection from: {}", addr);
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_link(state, stream, addr).await {
+                warn!("Link with {} closed: {:#}", addr, e);
+            }
+        });
+    }
+}
+
+/// 主动连接, 断开后按指数退避重连
+pub async fn dial(state: Arc<State>, addr: SocketAddr) {
+    let mut backoff = MIN_BACKOFF;
+    while !state.closing().is_cancelled() {
+        let started = Instant::now();
+        let result = match TcpStream::connect(addr).await {
+            Ok(stream) => run_link(state.clone(), stream, addr).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("Link with {} closed: {:#}", addr, e);
+        }
+        // 连上过一段时间才算稳定, 重新从最短的间隔开始
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+        info!("Reconnecting to {} in {:?}", addr, backoff);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+type LinkStream = Framed<TcpStream, FrameCodec<LinkFrame, LinkFrame>>;
+
+async fn run_link(state: Arc<State>, stream: TcpStream, addr: SocketAddr) -> Result<()> {
+    let codec = FrameCodec::with_max_length(MAX_LINK_FRAME);
+    let mut framed = Framed::new(stream, codec);
+    let server = timeout(HANDSHAKE_TIMEOUT, handshake(&mut framed, &state.links))
+        .await
+        .map_err(|_| anyhow!("handshake timed out"))??;
+
+    let (sender, mut receiver) = mpsc::channel(LINK_QUEUE);
+    let Some(link_id) = state.links.add(&server, sender) else {
+        let reason = format!("{} is already linked", server);
+        framed
+            .send(LinkFrame::Error {
+                reason: reason.clone(),
+            })
+            .await?;
+        return Err(anyhow!(reason));
+    };
+    info!("Linked with {} at {}", server, addr);
+
+    let (mut sink, mut stream) = framed.split();
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = receiver.recv().await {
+            if sink.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let result = async {
+        while let Some(frame) = stream.next().await {
+            match frame? {
+                LinkFrame::Relay(relay) => state.broadcast_remote(&server, *relay).await,
+                LinkFrame::Error { reason } => return Err(anyhow!(reason)),
+                frame => return Err(anyhow!("unexpected frame {:?}", frame)),
+            }
+        }
+        Ok(())
+    }
+    .await;
+    state.links.remove(&server, link_id);
+    writer.abort();
+    info!("Unlinked from {}", server);
+    result
+}
+
+/// 双方都证明自己知道密钥, 返回对方的名字
+async fn handshake(framed: &mut LinkStream, links: &Links) -> Result<String> {
+    let key = links.key.ok_or_else(|| anyhow!("federation is disabled"))?;
+    let nonce = nanoid!(32);
+    framed
+        .send(LinkFrame::Hello {
+            version: LINK_VERSION,
+            server: links.server.clone(),
+            nonce: nonce.clone(),
+        })
+        .await?;
+    let (server, remote_nonce) = match framed.next().await {
+        Some(Ok(LinkFrame::Hello {
+            version,
+            server,
+            nonce,
+        })) if version == LINK_VERSION => (server, nonce),
+        Some(Ok(LinkFrame::Hello { version, .. })) => {
+            return Err(anyhow!("unsupported link version {}", version))
+        }
+        Some(Ok(LinkFrame::Error { reason })) => return Err(anyhow!(reason)),
+        Some(Ok(frame)) => return Err(anyhow!("expect hello, got {:?}", frame)),
+        Some(Err(e)) => return Err(e.into()),
+        None => return Err(anyhow!("connection closed during handshake")),
+    };
+    if server == links.server {
+        return Err(anyhow!("refusing to link with itself"));
+    }
+    let mac = links.mac(&key, &remote_nonce, &links.server);
+    framed
+        .send(LinkFrame::Auth {
+            mac: mac.to_hex().to_string(),
+        })
+        .await?;
+    let expected = links.mac(&key, &nonce, &server);
+    let verified = match framed.next().await {
+        // Hash 的比较是常数时间的
+        Some(Ok(LinkFrame::Auth { mac })) => {
+            blake3::Hash::from_hex(mac).is_ok_and(|mac| mac == expected)
+        }
+        Some(Ok(LinkFrame::Error { reason })) => return Err(anyhow!(reason)),
+        Some(Ok(_)) => false,
+        Some(Err(e)) => return Err(e.into()),
+        None => return Err(anyhow!("connection closed during handshake")),
+    };
+    if !verified {
+        let reason = "link authentication failed".to_string();
+        framed
+            .send(LinkFrame::Error {
+                reason: reason.clone(),
+            })
+            .await?;
+        return Err(anyhow!("{} from {}", reason, server));
+    }
+    debug!("Link handshake with {} done", server);
+    Ok(server)
+}
+
+impl State {
+    /// 别的服务器转发过来的消息: 去重, 本地广播, 再转发给其他服务器
+    pub async fn broadcast_remote(&self, from: &str, mut relay: Relay) {
+        if relay.hops.iter().any(|hop| hop == self.links.server()) {
+            debug!(
+                "Dropping looped message {}:{} from {}",
+                relay.origin, relay.id, from
+            );
+            return;
+        }
+        if !self.links.first_seen(&relay.origin, relay.id) {
+            debug!(
+                "Dropping duplicate message {}:{} from {}",
+                relay.origin, relay.id, from
+            );
+            return;
+        }
+        let Some(room) = relay
+            .message
+            .room
+            .clone()
+            .filter(|_| is_relayed(&relay.message))
+        else {
+            warn!("Dropping invalid relayed message from {}", from);
+            return;
+        };
+        let kind = relay.message.kind;
+        let needs_target = matches!(
+            kind,
+            MessageKind::Edit | MessageKind::Delete | MessageKind::React | MessageKind::Unreact
+        );
+        if needs_target != relay.target.is_some()
+            || relay
+                .target
+                .as_ref()
+                .is_some_and(|(origin, _)| is_changed_by_origin(kind) && *origin != relay.origin)
+        {
+            warn!(
+                "Dropping invalid {:?} {}:{} from {}",
+                kind, relay.origin, relay.id, from
+            );
+            return;
+        }
+        // 回复的消息这边找不到就当普通消息, 作用的消息找不到就不用发给本机的人
+        let target = relay.target.as_ref().map(|target| self.links.local(target));
+        if target == Some(None) {
+            debug!(
+                "Message {}:{} targets an unknown message, not delivering locally",
+                relay.origin, relay.id
+            );
+        } else {
+            let mut message = relay.message.clone();
+            message.replayed = false;
+            message.author = Some(remote_author(&relay.origin));
+            message.reply_to = relay.reply_to.as_ref().and_then(|r| self.links.local(r));
+            message.target = target.flatten();
+            let message = self.deliver_room(&room, message);
+            if message.is_targetable() {
+                self.links.map(&relay.origin, relay.id, message.id);
+            }
+        }
+        relay.hops.push(self.links.server().to_string());
+        self.links.forward(relay);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::FileConfig;
+use anyhow::Result;
+use bytes::Bytes;
+use dashmap::DashMap;
+use nanoid::nanoid;
+use std::path::PathBuf;
+use tracing::info;
+
+/// 按内容的 blake3 hash 存文件, 同样的内容只存一份
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    max_size: u64,
+    // hash -> 最近一次上传时的文件名, 只在内存里, 重启后下载时用 hash 当名字
+    names: DashMap<String, String>,
+}
+
+/// 正在上传的文件, 收满 size 字节才算完成
+#[derive(Debug)]
+pub struct Upload {
+    pub name: String,
+    pub size: u64,
+    // 上传完发到哪个房间
+    pub room: String,
+    data: Vec<u8>,
+}
+
+impl FileStore {
+    pub fn open(config: &FileConfig) -> Result<Option<Self>> {
+        let Some(dir) = &config.dir else {
+            return Ok(None);
+        };
+        std::fs::create_dir_all(dir)?;
+        info!("Storing files in {}", dir.display());
+        Ok(Some(Self {
+            dir: dir.clone(),
+            max_size: config.max_size,
+            names: DashMap::new(),
+        }))
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// 返回 hex 格式的 hash, 已经有同样的内容时不再写
+    pub async fn put(&self, name: &str, data: &[u8]) -> Result<String> {
+        let hash = blake3::hash(data).to_hex().to_string();
+        let path = self.dir.join(&hash);
+        if !tokio::fs::try_exists(&path).await? {
+            // 同样的内容可能同时在上传, 临时文件不能重名
+            let tmp = self.dir.join(format!("{}.{}.tmp", hash, nanoid!(8)));
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+        }
+        self.names.insert(hash.clone(), name.to_string());
+        Ok(hash)
+    }
+
+    /// (文件名, 内容), 不是合法的 hash 或者没有这个文件时返回 None
+    pub async fn get(&self, hash: &str) -> Result<Option<(String, Bytes)>> {
+        // 只接受 hash, 不能用来读目录里的其他文件
+        let Ok(hash) = blake3::Hash::from_hex(hash) else {
+            return Ok(None);
+        };
+        let hash = hash.to_hex().to_string();
+        let data = match tokio::fs::read(self.dir.join(&hash)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let name = match self.names.get(&hash) {
+            Some(name) => name.clone(),
+            None => hash,
+        };
+        Ok(Some((name, Bytes::from(data))))
+    }
+}
+
+impl Upload {
+    pub fn new(name: String, size: u64, room: String) -> Self {
+        Self {
+            name,
+            size,
+            room,
+            data: Vec::new(),
+        }
+    }
+
+    /// 返回是否收完了, 超过声明的大小时返回 Err
+    pub fn push(&mut self, chunk: &[u8]) -> Result<bool, u64> {
+        if (self.data.len() + chunk.len()) as u64 > self.size {
+            return Err(self.size);
+        }
+        self.data.extend_from_slice(chunk);
+        Ok(self.data.len() as u64 == self.size)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
@@ -0,0 +1,142 @@
+use crate::config::HistoryConfig;
+use crate::message::{Message, MessageKind};
+use chrono::Utc;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// 每个房间最近的消息, 房间没人了也保留, 新加入的人可以看到上下文
+#[derive(Debug)]
+pub struct History {
+    config: HistoryConfig,
+    rooms: DashMap<String, Mutex<VecDeque<Arc<Message>>>>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> Self {
+        Self {
+            config,
+            rooms: DashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &HistoryConfig {
+        &self.config
+    }
+
+    pub fn push(&self, room: &str, message: Arc<Message>) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let entry = self.rooms.entry(room.to_string()).or_default();
+        let mut messages = entry.lock().unwrap();
+        if messages.len() == self.config.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// 按 id 找消息, 已经被删掉或者太旧不在历史里的返回 None
+    pub fn find(&self, room: &str, id: u64) -> Option<Arc<Message>> {
+        let entry = self.rooms.get(room)?;
+        let messages = entry.lock().unwrap();
+        let deleted = messages
+            .iter()
+            .any(|message| message.kind == MessageKind::Delete && message.target == Some(id));
+        if deleted {
+            return None;
+        }
+        messages.iter().find(|message| message.id == id).cloned()
+    }
+
+    /// 最近的 n 条消息 (从旧到新), 超过 max_age 的不要
+    pub fn recent(&self, room: &str, n: usize) -> Vec<Arc<Message>> {
+        let Some(entry) = self.rooms.get(room) else {
+            return vec![];
+        };
+        let mut messages = entry.lock().unwrap();
+        let oldest = Utc::now() - self.config.max_age;
+        while messages
+            .front()
+            .is_some_and(|message| message.timestamp < oldest)
+        {
+            messages.pop_front();
+        }
+        let skip = messages.len().saturating_sub(n);
+        messages.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    fn history(capacity: usize) -> History {
+        History::new(HistoryConfig {
+            capacity,
+            ..Default::default()
+        })
+    }
+
+    fn message(id: u64) -> Arc<Message> {
+        let mut message = Message::chat("lobby", "alice", format!("hello {}", id));
+        message.id = id;
+        Arc::new(message)
+    }
+
+    fn ids(messages: &[Arc<Message>]) -> Vec<u64> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn push_should_wrap_around_at_capacity() {
+        let history = history(3);
+        for id in 1..=5 {
+            history.push("lobby", message(id));
+        }
+        assert_eq!(ids(&history.recent("lobby", 10)), [3, 4, 5]);
+        assert_eq!(ids(&history.recent("lobby", 2)), [4, 5]);
+        assert!(history.find("lobby", 2).is_none());
+        assert_eq!(history.find("lobby", 3).unwrap().id, 3);
+    }
+
+    #[test]
+    fn rooms_should_be_separate() {
+        let history = history(2);
+        history.push("lobby", message(1));
+        history.push("rust", message(2));
+        history.push("rust", message(3));
+        history.push("rust", message(4));
+        assert_eq!(ids(&history.recent("lobby", 10)), [1]);
+        assert_eq!(ids(&history.recent("rust", 10)), [3, 4]);
+        assert!(history.recent("empty", 10).is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_should_keep_nothing() {
+        let history = history(0);
+        history.push("lobby", message(1));
+        assert!(history.recent("lobby", 10).is_empty());
+    }
+
+    #[test]
+    fn recent_should_drop_old_messages() {
+        let history = history(10);
+        let mut old = Message::chat("lobby", "alice", "old");
+        old.id = 1;
+        old.timestamp -= TimeDelta::hours(25);
+        history.push("lobby", Arc::new(old));
+        history.push("lobby", message(2));
+        assert_eq!(ids(&history.recent("lobby", 10)), [2]);
+        assert!(history.find("lobby", 1).is_none());
+    }
+
+    #[test]
+    fn find_should_skip_deleted() {
+        let history = history(10);
+        history.push("lobby", message(1));
+        history.push("lobby", Arc::new(Message::delete("lobby", "alice", 1)));
+        assert!(history.find("lobby", 1).is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::config::LimitConfig;
+use std::time::{Duration, Instant};
+
+/// 每个连接一个令牌桶, 每处理一行拿一个 token
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+    // 超限之后一直没缓过来, 桶满了才算结束
+    flooding: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Limit {
+    Allowed,
+    // wait 之后才会有下一个 token, 这一轮第一次超限时 first 为 true
+    Exceeded { wait: Duration, first: bool },
+}
+
+impl RateLimiter {
+    pub fn new(config: &LimitConfig) -> Self {
+        let burst = f64::from(config.burst);
+        Self {
+            // from_env 里已经保证了 rate > 0
+            rate: config.rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+            flooding: false,
+        }
+    }
+
+    pub fn take(&mut self) -> Limit {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+        if self.tokens >= self.burst {
+            self.flooding = false;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Limit::Allowed;
+        }
+        let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+        let first = !self.flooding;
+        self.flooding = true;
+        Limit::Exceeded { wait, first }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(&LimitConfig {
+            rate,
+            burst,
+            ..Default::default()
+        })
+    }
+
+    // 假装过去了一段时间, 不用真的 sleep
+    fn elapse(limiter: &mut RateLimiter, secs: f64) {
+        limiter.last_refill -= Duration::from_secs_f64(secs);
+    }
+
+    fn exceeded(limit: Limit) -> (Duration, bool) {
+        match limit {
+            Limit::Exceeded { wait, first } => (wait, first),
+            Limit::Allowed => panic!("should be limited"),
+        }
+    }
+
+    #[test]
+    fn burst_should_be_allowed_at_once() {
+        let mut limiter = limiter(0.001, 3);
+        for _ in 0..3 {
+            assert_eq!(limiter.take(), Limit::Allowed);
+        }
+        let (wait, first) = exceeded(limiter.take());
+        assert!(first);
+        assert!(wait > Duration::from_secs(900));
+        // 同一轮超限只有第一次 first 为 true
+        let (_, first) = exceeded(limiter.take());
+        assert!(!first);
+    }
+
+    #[test]
+    fn tokens_should_refill_over_time() {
+        let mut limiter = limiter(2.0, 4);
+        for _ in 0..4 {
+            assert_eq!(limiter.take(), Limit::Allowed);
+        }
+        let (wait, _) = exceeded(limiter.take());
+        assert!(wait <= Duration::from_millis(500));
+        elapse(&mut limiter, 1.0);
+        assert_eq!(limiter.take(), Limit::Allowed);
+        assert_eq!(limiter.take(), Limit::Allowed);
+        exceeded(limiter.take());
+    }
+
+    #[test]
+    fn refill_should_not_exceed_burst() {
+        let mut limiter = limiter(2.0, 4);
+        elapse(&mut limiter, 100.0);
+        for _ in 0..4 {
+            assert_eq!(limiter.take(), Limit::Allowed);
+        }
+        exceeded(limiter.take());
+    }
+
+    #[test]
+    fn flooding_should_end_when_bucket_is_full() {
+        let mut limiter = limiter(2.0, 2);
+        limiter.take();
+        limiter.take();
+        assert!(exceeded(limiter.take()).1);
+        // 补回一个 token 还不算缓过来
+        elapse(&mut limiter, 0.5);
+        assert_eq!(limiter.take(), Limit::Allowed);
+        assert!(!exceeded(limiter.take()).1);
+        elapse(&mut limiter, 1.0);
+        assert_eq!(limiter.take(), Limit::Allowed);
+        limiter.take();
+        assert!(exceeded(limiter.take()).1);
+    }
+}
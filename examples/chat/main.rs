@@ -0,0 +1,567 @@
+mod account;
+mod admin;
+mod ban;
+mod command;
+mod config;
+mod federation;
+mod files;
+mod history;
+mod limiter;
+mod message;
+mod outbox;
+mod state;
+mod transcript;
+mod transport;
+mod ws;
+
+use anyhow::{anyhow, Result};
+use axum::serve::Listener;
+use ban::BanList;
+use command::{Context, Flow, Registry};
+use config::{Config, FloodPenalty};
+use ecosystem::shutdown;
+use ecosystem::tls::{TlsAcceptor, TlsListener};
+use futures::future;
+use futures::StreamExt;
+use limiter::Limit;
+use message::{Event, Message, SystemMessage};
+use state::{Peer, Presence, State, SHUTDOWN_NOTICE};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, info, warn};
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+use transcript::ExportFormat;
+use transport::{Incoming, InvalidChunk, LineTooLong, Protocol, Transport};
+
+const DEFAULT_ROOM: &str = "lobby";
+// 多久检查一次证书文件有没有更新
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = fmt::Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+
+    let config = Config::from_env()?;
+    // cargo run --example chat -- export <from> <to> [json|text]
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("export") {
+        return export(&config, &args[1..]);
+    }
+
+    let listener = TcpListener::bind(config.listen_addr).await?;
+    info!("Listening on: {}", config.listen_addr);
+
+    let accounts = account::open(&config.account.store).await?;
+    let bans = BanList::open(config.moderation.ban_file.clone()).await?;
+    let state = Arc::new(State::try_new(&config, accounts, bans)?);
+    let registry = Arc::new(Registry::default());
+    let shutdown = shutdown::on_signal();
+    // ws, framed 和 admin 的 server, 退出时等它们收尾
+    let mut servers = vec![];
+
+    // 配了证书时所有面向客户端的端口都走 TLS
+    let tls = match &config.tls {
+        Some(tls) => {
+            let acceptor = Arc::new(TlsAcceptor::new(tls.clone())?);
+            tokio::spawn(acceptor.clone().watch(TLS_RELOAD_INTERVAL));
+            Some(acceptor)
+        }
+        None => None,
+    };
+
+    if let Some(ws_addr) = config.ws_addr {
+        let listener = TcpListener::bind(ws_addr).await?;
+        info!("WebSocket listening on: {}", ws_addr);
+        let app = ws::router(state.clone(), registry.clone());
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            if let Err(e) = ws::serve(listener, tls, app, shutdown).await {
+                warn!("WebSocket server exited with error: {}", e);
+            }
+        }));
+    }
+
+    if let Some(framed_addr) = config.framed_addr {
+        let listener = TcpListener::bind(framed_addr).await?;
+        info!("Framed protocol listening on: {}", framed_addr);
+        let state = state.clone();
+        let registry = registry.clone();
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            let protocol = Protocol::Framed;
+            if let Err(e) = serve(listener, tls, state, registry, protocol, shutdown).await {
+                warn!("Framed server exited with error: {}", e);
+            }
+        }));
+    }
+
+    if let Some(token) = &config.admin.token {
+        let listener = TcpListener::bind(config.admin.listen_addr).await?;
+        info!("Admin API listening on: {}", config.admin.listen_addr);
+        let app = admin::router(state.clone(), token);
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            if let Err(e) = ws::serve(listener, tls, app, shutdown).await {
+                warn!("Admin server exited with error: {}", e);
+            }
+        }));
+    }
+
+    if config.federation.secret.is_some() {
+        if let Some(link_addr) = config.federation.listen_addr {
+            let listener = TcpListener::bind(link_addr).await?;
+            info!(
+                "Federation {} listening on: {}",
+                config.federation.server_name, link_addr
+            );
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = federation::serve(listener, state).await {
+                    warn!("Federation server exited with error: {}", e);
+                }
+            });
+        }
+        for peer in config.federation.peers.iter().copied() {
+            tokio::spawn(federation::dial(state.clone(), peer));
+        }
+    }
+
+    serve(
+        listener,
+        tls,
+        state.clone(),
+        registry,
+        Protocol::Lines,
+        shutdown,
+    )
+    .await?;
+
+    // 不再接受新连接, 通知所有客户端之后等它们断开
+    state.shutdown(config.shutdown_timeout).await;
+    if timeout(config.shutdown_timeout, future::join_all(servers))
+        .await
+        .is_err()
+    {
+        warn!("Servers did not stop in {:?}", config.shutdown_timeout);
+    }
+    info!("Bye");
+    Ok(())
+}
+
+async fn serve(
+    listener: TcpListener,
+    tls: Option<Arc<TlsAcceptor>>,
+    state: Arc<State>,
+    registry: Arc<Registry>,
+    protocol: Protocol,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    match tls {
+        Some(acceptor) => {
+            let listener = TlsListener::new(listener, acceptor)?;
+            serve_on(listener, state, registry, protocol, shutdown).await
+        }
+        None => serve_on(listener, state, registry, protocol, shutdown).await,
+    }
+}
+
+async fn serve_on<L>(
+    mut listener: L,
+    state: Arc<State>,
+    registry: Arc<Registry>,
+    protocol: Protocol,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    L: Listener<Addr = SocketAddr>,
+    L::Io: Sync,
+{
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        info!("Got connection from: {}", addr);
+        let clients = state.clients.clone();
+        let state = state.clone();
+        let registry = registry.clone();
+        clients.spawn(async move {
+            let limits = &state.config().limits;
+            let result = match Transport::accept(stream, protocol, limits).await {
+                Ok(transport) => handle_client(state, registry, addr, transport).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Client error: {}", e);
+            }
+        });
+    }
+}
+
+fn export(config: &Config, args: &[String]) -> Result<()> {
+    let dir = config
+        .transcript_dir
+        .as_ref()
+        .ok_or_else(|| anyhow!("transcript is disabled"))?;
+    let (from, to, format) = match args {
+        [from, to] => (from, to, ExportFormat::Text),
+        [from, to, format] => {
+            let format = match format.as_str() {
+                "json" => ExportFormat::Json,
+                "text" => ExportFormat::Text,
+                _ => return Err(anyhow!("unknown format {}, expect json or text", format)),
+            };
+            (from, to, format)
+        }
+        _ => return Err(anyhow!("usage: chat export <from> <to> [json|text]")),
+    };
+    let from = transcript::parse_time(from, false)?;
+    let to = transcript::parse_time(to, true)?;
+    transcript::export(dir, from, to, format, std::io::stdout().lock())
+}
+
+async fn handle_client(
+    state: Arc<State>,
+    registry: Arc<Registry>,
+    addr: SocketAddr,
+    mut transport: Transport,
+) -> Result<()> {
+    if let Some(ban) = state.bans.check(addr.ip(), None).await {
+        info!("Rejected banned address {}", addr);
+        let reason = ban.describe();
+        transport.send(Event::Error { id: None, reason }).await?;
+        return Ok(());
+    }
+    let login = tokio::select! {
+        login = registry.login(&state, addr, &mut transport) => Some(login),
+        _ = state.closing().cancelled() => None,
+    };
+    let session = match login {
+        Some(Ok(session)) => session,
+        Some(Err(e)) => {
+            warn!("failed to read username from {}: {}", addr, e);
+            return Ok(());
+        }
+        None => {
+            let reason = SHUTDOWN_NOTICE.to_string();
+            transport.send(Event::Error { id: None, reason }).await?;
+            return Ok(());
+        }
+    };
+
+    if let Some(ban) = state.bans.check(addr.ip(), Some(&session.username)).await {
+        info!("Rejected banned user {} from {}", session.username, addr);
+        let reason = ban.describe();
+        transport.send(Event::Error { id: None, reason }).await?;
+        return Ok(());
+    }
+
+    let mut peer = state.add(addr, session.username, transport);
+    // 登录完的时候已经开始退出了, State::shutdown 可能没看到这个连接
+    if state.closing().is_cancelled() {
+        state.disconnect(addr, SHUTDOWN_NOTICE);
+    }
+    if session.account.is_some() {
+        state.set_account(addr, session.account.clone());
+        peer.account = session.account;
+    }
+    state
+        .broadcast_system(SystemMessage::UserJoin {
+            addr,
+            room: DEFAULT_ROOM.to_string(),
+            username: peer.username.clone(),
+        })
+        .await;
+    peer.room = Some(DEFAULT_ROOM.to_string());
+
+    let mut ticker = interval(state.config().heartbeat.ping_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker.tick().await;
+
+    // 客户端收取消息
+    loop {
+        let incoming = tokio::select! {
+            incoming = peer.stream.next() => incoming,
+            _ = ticker.tick() => {
+                if heartbeat(&state, addr, &mut peer).await == Flow::Quit {
+                    break;
+                }
+                continue;
+            }
+            _ = peer.closed.cancelled() => break,
+        };
+        if matches!(incoming, Some(Ok(_))) {
+            peer.last_seen = Instant::now();
+        }
+        let inbound = match incoming {
+            Some(Ok(Incoming::Line(inbound))) => inbound,
+            Some(Ok(Incoming::Pong(nonce))) => {
+                debug!("Got pong {} from {}", nonce, addr);
+                continue;
+            }
+            Some(Ok(Incoming::Chunk { id, data })) => {
+                let flow = match peer.upload {
+                    // 没在上传时和普通的一行一样限速
+                    None => rate_limit(&state, addr, &mut peer, id).await,
+                    Some(_) => None,
+                };
+                if flow.is_none() {
+                    handle_chunk(&state, addr, &mut peer, id, &data).await;
+                }
+                // 每个 Chunk 都有 Ack, 数量多, 队列满了时等对方收而不是丢掉
+                state.send_all(addr, id.map(Event::Ack)).await;
+                if flow == Some(Flow::Quit) {
+                    break;
+                }
+                continue;
+            }
+            None => break,
+            Some(Err(e)) => {
+                if let Some(too_long) = e.downcast_ref::<LineTooLong>() {
+                    warn!(
+                        "Peer {} sent a line longer than {} bytes",
+                        addr, too_long.limit
+                    );
+                    state.error(addr, too_long.id, too_long.to_string()).await;
+                    if let Some(id) = too_long.id {
+                        state.send_to(addr, Event::Ack(id)).await;
+                    }
+                    continue;
+                }
+                if let Some(invalid) = e.downcast_ref::<InvalidChunk>() {
+                    // 坏了一块整个文件就不对了
+                    if let Some(upload) = peer.upload.take() {
+                        warn!("Peer {} sent an invalid chunk of {}", addr, upload.name);
+                    }
+                    state.error(addr, invalid.id, invalid.to_string()).await;
+                    continue;
+                }
+                if let Some(ecosystem::Error::FrameTooLong(limit)) = e.downcast_ref() {
+                    warn!(
+                        "Peer {} sent a frame longer than {} bytes, disconnecting",
+                        addr, limit
+                    );
+                    state.disconnect(addr, &e.to_string());
+                    break;
+                }
+                warn!("failed to read line from {}: {}", addr, e);
+                break;
+            }
+        };
+        // 说话了就不再是 idle
+        peer.last_active = Instant::now();
+        if state.presence(addr) == Some(Presence::Idle) {
+            state.set_presence(addr, Presence::Online).await;
+        }
+        // 刷屏时按配置的 penalty 处理, 返回 None 才继续处理这一行
+        let flow = match rate_limit(&state, addr, &mut peer, inbound.id).await {
+            Some(flow) => flow,
+            None => {
+                handle_line(
+                    &state,
+                    &registry,
+                    addr,
+                    &mut peer,
+                    inbound.id,
+                    &inbound.line,
+                )
+                .await
+            }
+        };
+        if let Some(id) = inbound.id {
+            state.send_to(addr, Event::Ack(id)).await;
+        }
+        if flow == Flow::Quit {
+            break;
+        }
+    }
+    for room in state.remove(addr) {
+        state
+            .broadcast_system(SystemMessage::UserLeave {
+                addr,
+                room,
+                username: peer.username.clone(),
+            })
+            .await;
+    }
+    Ok(())
+}
+
+/// 定时检查: 太久没收到任何东西就断开, 否则发 ping; 太久没说话标记为 idle
+async fn heartbeat(state: &State, addr: SocketAddr, peer: &mut Peer) -> Flow {
+    let config = &state.config().heartbeat;
+    let silent = peer.last_seen.elapsed();
+    if silent >= config.timeout {
+        info!(
+            "Peer {} ({}) sent nothing for {:?}, disconnecting",
+            addr, peer.username, silent
+        );
+        state.disconnect(addr, "Idle timeout, disconnecting");
+        return Flow::Quit;
+    }
+    if silent >= config.ping_interval {
+        peer.pings += 1;
+        state.send_to(addr, Event::Ping(peer.pings)).await;
+    }
+    if peer.last_active.elapsed() >= config.idle_after
+        && state.presence(addr) == Some(Presence::Online)
+    {
+        state.set_presence(addr, Presence::Idle).await;
+    }
+    Flow::Continue
+}
+
+async fn rate_limit(
+    state: &State,
+    addr: SocketAddr,
+    peer: &mut Peer,
+    frame_id: Option<u64>,
+) -> Option<Flow> {
+    let Limit::Exceeded { wait, first } = peer.limiter.take() else {
+        return None;
+    };
+    let penalty = state.config().limits.penalty;
+    if first {
+        warn!(
+            "Peer {} ({}) is flooding, penalty: {:?}",
+            addr, peer.username, penalty
+        );
+    }
+    match penalty {
+        FloodPenalty::Throttle => {
+            // 等 token 补上再处理, 这段时间不读 socket, TCP 会让对方慢下来
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = peer.closed.cancelled() => return Some(Flow::Quit),
+            }
+            peer.limiter.take();
+            None
+        }
+        FloodPenalty::Warn => {
+            state
+                .error(
+                    addr,
+                    frame_id,
+                    "You are sending messages too fast, slow down",
+                )
+                .await;
+            Some(Flow::Continue)
+        }
+        FloodPenalty::Kick => {
+            warn!("Kicking peer {} ({}) for flooding", addr, peer.username);
+            state.disconnect(addr, "You were kicked for flooding");
+            Some(Flow::Quit)
+        }
+    }
+}
+
+/// 上传的文件内容, 收完之后存起来, 发到开始上传时的房间
+async fn handle_chunk(
+    state: &State,
+    addr: SocketAddr,
+    peer: &mut Peer,
+    frame_id: Option<u64>,
+    data: &[u8],
+) {
+    let Some(upload) = peer.upload.as_mut() else {
+        state
+            .error(
+                addr,
+                frame_id,
+                "No upload in progress, /upload <size> <name> first",
+            )
+            .await;
+        return;
+    };
+    match upload.push(data) {
+        Ok(false) => return,
+        Ok(true) => {}
+        Err(size) => {
+            peer.upload = None;
+            let reason = format!("Upload is larger than {} bytes, cancelled", size);
+            state.error(addr, frame_id, reason).await;
+            return;
+        }
+    }
+    let (Some(upload), Some(files)) = (peer.upload.take(), &state.files) else {
+        return;
+    };
+    match files.put(&upload.name, upload.data()).await {
+        Ok(hash) => {
+            info!(
+                "Peer {} ({}) uploaded {} ({} bytes) as {}",
+                addr, peer.username, upload.name, upload.size, hash
+            );
+            let content = format!(
+                "{} ({} bytes), /download {}",
+                upload.name, upload.size, hash
+            );
+            let message = Message::file(&upload.room, &peer.username, content).by(peer.author());
+            state.broadcast(&upload.room, message).await;
+        }
+        Err(e) => {
+            warn!("Failed to store file from {}: {:#}", addr, e);
+            state
+                .error(
+                    addr,
+                    frame_id,
+                    "Internal server error, please try again later",
+                )
+                .await;
+        }
+    }
+}
+
+async fn handle_line(
+    state: &State,
+    registry: &Registry,
+    addr: SocketAddr,
+    peer: &mut Peer,
+    frame_id: Option<u64>,
+    line: &str,
+) -> Flow {
+    let mut ctx = Context {
+        state,
+        registry,
+        addr,
+        peer,
+        frame_id,
+    };
+    if let Some(flow) = registry.dispatch(&mut ctx, line).await {
+        return flow;
+    }
+    // `//` 开头的转义成普通消息
+    let content = line
+        .strip_prefix('/')
+        .filter(|l| l.starts_with('/'))
+        .unwrap_or(line);
+    let Some(room) = peer.room.clone() else {
+        state
+            .error(
+                addr,
+                frame_id,
+                "You are not in any room, /join <room> first",
+            )
+            .await;
+        return Flow::Continue;
+    };
+    if state.is_muted(&peer.username) {
+        state.error(addr, frame_id, "You are muted").await;
+        return Flow::Continue;
+    }
+    let message = Message::chat(&room, &peer.username, content).by(peer.author());
+    state.broadcast(&room, message).await;
+    Flow::Continue
+}
@@ -0,0 +1,86 @@
+use crate::ban::Ban;
+use crate::state::Presence;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+pub use ecosystem::chat::{Message, MessageKind, SERVER_NAME};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum SystemMessage {
+    UserJoin {
+        addr: SocketAddr,
+        room: String,
+        username: String,
+    },
+    UserLeave {
+        addr: SocketAddr,
+        room: String,
+        username: String,
+    },
+    Rename {
+        addr: SocketAddr,
+        from: String,
+        to: String,
+    },
+    // online / away / idle 之间切换
+    Presence {
+        addr: SocketAddr,
+        username: String,
+        presence: Presence,
+    },
+    // 以下是 operator 的操作, by 是操作的人, 通知发到被操作的人所在的房间
+    Kick {
+        addr: SocketAddr,
+        username: String,
+        by: String,
+        reason: Option<String>,
+    },
+    Ban {
+        addr: SocketAddr,
+        username: String,
+        ban: Ban,
+    },
+    Mute {
+        username: String,
+        by: String,
+        // None 表示一直禁言
+        expires_at: Option<DateTime<Utc>>,
+    },
+    Unmute {
+        username: String,
+        by: String,
+    },
+    Topic {
+        room: String,
+        by: String,
+        topic: String,
+    },
+    // admin API 发的全服公告, 发给所有在线的人
+    Announce {
+        by: String,
+        text: String,
+    },
+}
+
+/// 发给某个连接的内容, 由 transport 决定怎么编码
+#[derive(Debug, Clone)]
+pub enum Event {
+    Message(Arc<Message>),
+    Prompt(String),
+    // id 是出错的那一帧
+    Error {
+        id: Option<u64>,
+        reason: String,
+    },
+    Ack(u64),
+    // 心跳, nonce 递增
+    Ping(u64),
+    // /download 的文件头, 后面跟着若干个 Chunk
+    File {
+        hash: String,
+        name: String,
+        size: u64,
+    },
+    Chunk(Bytes),
+}
@@ -0,0 +1,263 @@
+use crate::config::{OutboxConfig, SlowConsumerPolicy};
+use crate::message::{Event, Message};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// 每个 peer 的发送队列, 放不进去时按 policy 处理, 不会阻塞 broadcast
+#[derive(Debug)]
+pub struct Outbox {
+    config: OutboxConfig,
+    inner: Mutex<Inner>,
+    notify: Notify,
+    // 每取走一条通知一次, 等队列空出位置的人用
+    drained: Notify,
+    closed: CancellationToken,
+    // 一共丢了多少条
+    dropped: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    queue: VecDeque<Event>,
+    // 队列从什么时候开始一直是满的
+    full_since: Option<Instant>,
+    // 上次提醒之后又丢了多少条
+    unreported: u64,
+    last_warning: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Push {
+    Queued,
+    // 丢了一条消息, 第一次开始丢的时候为 true
+    Dropped { first: bool },
+    // 满的时间太长, 需要断开
+    Disconnect,
+}
+
+impl Outbox {
+    pub fn new(config: OutboxConfig) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(Inner {
+                queue: VecDeque::with_capacity(config.capacity),
+                full_since: None,
+                unreported: 0,
+                last_warning: None,
+            }),
+            config,
+            notify: Notify::new(),
+            drained: Notify::new(),
+            closed: CancellationToken::new(),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub fn push(&self, event: Event) -> Push {
+        if self.closed.is_cancelled() {
+            return Push::Queued;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.queue.len() < self.config.capacity {
+            inner.queue.push_back(event);
+            inner.full_since = None;
+            drop(inner);
+            self.notify.notify_one();
+            return Push::Queued;
+        }
+
+        let full_since = *inner.full_since.get_or_insert_with(Instant::now);
+        match self.config.policy {
+            SlowConsumerPolicy::DropOldest => {
+                inner.queue.pop_front();
+                inner.queue.push_back(event);
+            }
+            SlowConsumerPolicy::DropNew => {}
+            SlowConsumerPolicy::Disconnect(after) if full_since.elapsed() >= after => {
+                return Push::Disconnect;
+            }
+            SlowConsumerPolicy::Disconnect(_) => {}
+        }
+        inner.unreported += 1;
+        let first = inner.unreported == 1;
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        Push::Dropped { first }
+    }
+
+    /// 关闭之后返回 None, 队列里剩下的也不再发送
+    pub async fn pop(&self) -> Option<Event> {
+        loop {
+            if let Some(event) = self.try_pop() {
+                self.drained.notify_waiters();
+                return Some(event);
+            }
+            if self.closed.is_cancelled() {
+                return None;
+            }
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = self.closed.cancelled() => {}
+            }
+        }
+    }
+
+    fn try_pop(&self) -> Option<Event> {
+        let mut inner = self.inner.lock().unwrap();
+        let event = inner.queue.pop_front()?;
+        // 丢过消息就提醒一下, 但不要太频繁
+        let can_warn = inner
+            .last_warning
+            .is_none_or(|at| at.elapsed() >= self.config.warn_interval);
+        if inner.unreported > 0 && can_warn {
+            let dropped = std::mem::take(&mut inner.unreported);
+            inner.last_warning = Some(Instant::now());
+            inner.queue.push_front(event);
+            let warning = format!(
+                "Warning: you are lagging behind, {} messages were dropped",
+                dropped
+            );
+            return Some(Event::Message(Arc::new(Message::reply(warning))));
+        }
+        Some(event)
+    }
+
+    /// 清空队列, 只把最后一条 (比如断开原因) 发出去, 重复关闭时保留第一次的
+    pub fn close(&self, last: Option<Event>) {
+        let mut inner = self.inner.lock().unwrap();
+        if self.closed.is_cancelled() {
+            return;
+        }
+        inner.queue.clear();
+        inner.queue.extend(last);
+        self.closed.cancel();
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    pub fn closed(&self) -> CancellationToken {
+        self.closed.clone()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// 还没发出去的条数
+    pub fn queued(&self) -> usize {
+        self.inner.lock().unwrap().queue.len()
+    }
+
+    /// 等到队列不到一半满, 大量的数据 (比如文件) 分批放进来, 不会被 policy 丢掉
+    pub async fn wait_room(&self) {
+        loop {
+            // 先注册再检查, 不会错过中间的通知
+            let drained = self.drained.notified();
+            if self.closed.is_cancelled() || self.queued() < (self.config.capacity / 2).max(1) {
+                return;
+            }
+            tokio::select! {
+                _ = drained => {}
+                _ = self.closed.cancelled() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn outbox(capacity: usize, policy: SlowConsumerPolicy) -> Arc<Outbox> {
+        Outbox::new(OutboxConfig {
+            capacity,
+            policy,
+            warn_interval: Duration::from_secs(3600),
+        })
+    }
+
+    fn event(text: &str) -> Event {
+        Event::Prompt(text.to_string())
+    }
+
+    fn texts(outbox: &Outbox) -> Vec<String> {
+        std::iter::from_fn(|| outbox.try_pop())
+            .map(|event| match event {
+                Event::Prompt(text) => text,
+                Event::Message(message) => message.content.clone(),
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_should_keep_newest() {
+        let outbox = outbox(2, SlowConsumerPolicy::DropOldest);
+        assert_eq!(outbox.push(event("1")), Push::Queued);
+        assert_eq!(outbox.push(event("2")), Push::Queued);
+        assert_eq!(outbox.push(event("3")), Push::Dropped { first: true });
+        assert_eq!(outbox.push(event("4")), Push::Dropped { first: false });
+        assert_eq!(outbox.dropped(), 2);
+        assert_eq!(
+            texts(&outbox),
+            [
+                "Warning: you are lagging behind, 2 messages were dropped",
+                "3",
+                "4"
+            ]
+        );
+    }
+
+    #[test]
+    fn drop_new_should_keep_oldest() {
+        let outbox = outbox(2, SlowConsumerPolicy::DropNew);
+        outbox.push(event("1"));
+        outbox.push(event("2"));
+        assert_eq!(outbox.push(event("3")), Push::Dropped { first: true });
+        assert_eq!(outbox.queued(), 2);
+        assert_eq!(texts(&outbox)[1..], ["1", "2"]);
+    }
+
+    #[test]
+    fn disconnect_should_drop_until_full_for_too_long() {
+        let outbox = outbox(1, SlowConsumerPolicy::Disconnect(Duration::from_secs(3600)));
+        outbox.push(event("1"));
+        assert_eq!(outbox.push(event("2")), Push::Dropped { first: true });
+        assert_eq!(outbox.queued(), 1);
+    }
+
+    #[test]
+    fn disconnect_should_disconnect_after_timeout() {
+        let outbox = outbox(1, SlowConsumerPolicy::Disconnect(Duration::ZERO));
+        outbox.push(event("1"));
+        assert_eq!(outbox.push(event("2")), Push::Disconnect);
+        assert_eq!(outbox.dropped(), 0);
+    }
+
+    #[test]
+    fn room_should_reset_full_since() {
+        let outbox = outbox(1, SlowConsumerPolicy::Disconnect(Duration::from_millis(50)));
+        outbox.push(event("1"));
+        outbox.push(event("2"));
+        std::thread::sleep(Duration::from_millis(60));
+        // 取走一条之后又能放进去, 重新开始计时
+        outbox.try_pop();
+        outbox.try_pop();
+        assert_eq!(outbox.push(event("3")), Push::Queued);
+        assert!(matches!(outbox.push(event("4")), Push::Dropped { .. }));
+    }
+
+    #[tokio::test]
+    async fn close_should_only_send_last() {
+        let outbox = outbox(4, SlowConsumerPolicy::DropOldest);
+        outbox.push(event("1"));
+        outbox.push(event("2"));
+        outbox.close(Some(event("bye")));
+        outbox.close(Some(event("again")));
+        assert!(matches!(outbox.pop().await, Some(Event::Prompt(text)) if text == "bye"));
+        assert!(outbox.pop().await.is_none());
+    }
+}
@@ -0,0 +1,663 @@
+use crate::account::AccountStore;
+use crate::ban::{self, BanList};
+use crate::config::Config;
+use crate::federation::Links;
+use crate::files::{FileStore, Upload};
+use crate::history::History;
+use crate::limiter::RateLimiter;
+use crate::message::{Event, Message, SystemMessage};
+use crate::outbox::{Outbox, Push};
+use crate::transcript::{self, Transcript};
+use crate::transport::{InboundStream, Transport};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
+use futures::SinkExt;
+use nanoid::nanoid;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+pub const SHUTDOWN_NOTICE: &str = "Server is shutting down";
+
+#[derive(Debug)]
+pub struct State {
+    pub peers: DashMap<SocketAddr, PeerHandle>,
+    // room name -> members, 房间没人时自动删除
+    pub rooms: DashMap<String, DashSet<SocketAddr>>,
+    // lowercase username -> addrs, 名字可以重复, 私聊时用来找人
+    pub names: DashMap<String, DashSet<SocketAddr>>,
+    pub history: History,
+    pub accounts: Arc<dyn AccountStore>,
+    pub bans: BanList,
+    // federation 里连着的其他服务器
+    pub links: Links,
+    // None 表示不能传文件
+    pub files: Option<FileStore>,
+    // lowercase username -> 禁言到什么时候, None 表示一直
+    mutes: DashMap<String, Option<DateTime<Utc>>>,
+    // room name -> topic, 房间没人了也保留
+    topics: DashMap<String, String>,
+    transcript: Option<Transcript>,
+    // 所有连接的 handle_client, 退出时等它们结束
+    pub clients: TaskTracker,
+    // 开始退出之后 cancel, 还没登录完的连接也要停下来
+    closing: CancellationToken,
+    // 消息 id, 重启后从聊天记录里接着往下分配
+    next_id: AtomicU64,
+    config: Config,
+}
+
+/// State 里保存的 peer 信息, 给其他连接查询用
+#[derive(Debug)]
+pub struct PeerHandle {
+    pub username: String,
+    // 登录的账号, 游客为 None
+    pub account: Option<String>,
+    pub presence: Presence,
+    pub outbox: Arc<Outbox>,
+    // 登录完成的时间
+    pub connected_at: DateTime<Utc>,
+}
+
+/// /who 里显示的状态, idle 是太久没说话时自动设置的
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Presence {
+    Online,
+    // /away 时可以带一句话
+    Away(Option<String>),
+    Idle,
+}
+
+pub struct Peer {
+    pub username: String,
+    pub account: Option<String>,
+    // 这次连接的 id, 游客发的消息记在它名下
+    pub session: String,
+    // 当前发言的房间
+    pub room: Option<String>,
+    // stream
+    pub stream: InboundStream,
+    // 服务端要断开这个连接时 cancel
+    pub closed: CancellationToken,
+    pub limiter: RateLimiter,
+    // 最后一次收到任何东西 (包括 pong)
+    pub last_seen: Instant,
+    // 最后一次说话或者用命令
+    pub last_active: Instant,
+    // 发出去的 ping 的个数, 也用作 nonce
+    pub pings: u64,
+    // /upload 之后还没收完的文件
+    pub upload: Option<Upload>,
+}
+
+impl State {
+    /// 有聊天记录目录时, 用落盘的记录恢复内存里的历史消息
+    pub fn try_new(
+        config: &Config,
+        accounts: Arc<dyn AccountStore>,
+        bans: BanList,
+    ) -> Result<Self> {
+        let history = History::new(config.history.clone());
+        let mut last_id = 0;
+        let transcript = match &config.transcript_dir {
+            Some(dir) => {
+                let now = Utc::now();
+                let messages = transcript::load(dir, now - config.history.max_age, now)?;
+                info!(
+                    "Restored {} messages from {}",
+                    messages.len(),
+                    dir.display()
+                );
+                for message in messages {
+                    last_id = last_id.max(message.id);
+                    if let Some(room) = message.room.clone() {
+                        history.push(&room, Arc::new(message));
+                    }
+                }
+                Some(Transcript::open(dir)?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            peers: DashMap::new(),
+            rooms: DashMap::new(),
+            names: DashMap::new(),
+            history,
+            accounts,
+            bans,
+            links: Links::new(&config.federation),
+            files: FileStore::open(&config.files)?,
+            mutes: DashMap::new(),
+            topics: DashMap::new(),
+            transcript,
+            clients: TaskTracker::new(),
+            closing: CancellationToken::new(),
+            next_id: AtomicU64::new(last_id + 1),
+            config: config.clone(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// 分配 id, 之后消息就不再修改了
+    fn publish(&self, mut message: Message) -> Arc<Message> {
+        message.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Arc::new(message)
+    }
+
+    /// 发给房间里的人, 同时转发给 federation 的其他服务器
+    pub async fn broadcast(&self, room: &str, message: Message) {
+        let message = self.deliver_room(room, message);
+        self.links.relay(&message);
+    }
+
+    /// 只发给本机的连接, 返回分配了 id 的消息
+    pub fn deliver_room(&self, room: &str, message: Message) -> Arc<Message> {
+        let message = self.publish(message);
+        self.history.push(room, message.clone());
+        if let Some(transcript) = &self.transcript {
+            transcript.append(message.clone());
+        }
+        // 先收集 outbox, 不要在 push 的时候持有 dashmap 的锁
+        let peers: Vec<_> = match self.rooms.get(room) {
+            Some(members) => members
+                .iter()
+                .filter_map(|addr| {
+                    self.peers
+                        .get(addr.key())
+                        .map(|peer| (*addr.key(), peer.outbox.clone()))
+                })
+                .collect(),
+            None => vec![],
+        };
+        for (addr, outbox) in peers {
+            self.deliver(addr, &outbox, Event::Message(message.clone()));
+        }
+        message
+    }
+
+    pub async fn send_to(&self, addr: SocketAddr, event: Event) {
+        let outbox = match self.peers.get(&addr) {
+            Some(peer) => peer.outbox.clone(),
+            None => return,
+        };
+        self.deliver(addr, &outbox, event);
+    }
+
+    /// 放进 peer 的队列, 不会因为某个客户端慢而阻塞
+    fn deliver(&self, addr: SocketAddr, outbox: &Outbox, event: Event) {
+        match outbox.push(event) {
+            Push::Queued | Push::Dropped { first: false } => {}
+            Push::Dropped { first: true } => {
+                warn!(
+                    "Peer {} is lagging, dropping messages ({} dropped so far)",
+                    addr,
+                    outbox.dropped()
+                );
+            }
+            Push::Disconnect => {
+                warn!(
+                    "Disconnecting slow peer {} after dropping {} messages",
+                    addr,
+                    outbox.dropped()
+                );
+                self.disconnect(addr, "You were disconnected for being too slow");
+            }
+        }
+    }
+
+    /// 通知所有连接服务器要关了, 最多等 timeout 让它们把剩下的消息发完
+    pub async fn shutdown(&self, timeout_after: Duration) {
+        // 先 cancel 再取 peers, 之后登录完的连接自己会看到 closing
+        self.closing.cancel();
+        let addrs: Vec<SocketAddr> = self.peers.iter().map(|peer| *peer.key()).collect();
+        info!("Disconnecting {} peers", addrs.len());
+        for addr in addrs {
+            self.disconnect(addr, SHUTDOWN_NOTICE);
+        }
+        self.clients.close();
+        if timeout(timeout_after, self.clients.wait()).await.is_err() {
+            warn!(
+                "{} clients still connected after {:?}, exiting anyway",
+                self.clients.len(),
+                timeout_after
+            );
+        }
+        if let Some(transcript) = &self.transcript {
+            transcript.flush().await;
+        }
+    }
+
+    pub fn closing(&self) -> &CancellationToken {
+        &self.closing
+    }
+
+    /// 断开连接, reason 会作为最后一条消息发给对方
+    pub fn disconnect(&self, addr: SocketAddr, reason: &str) {
+        if let Some(peer) = self.peers.get(&addr) {
+            let last = Event::Error {
+                id: None,
+                reason: reason.to_string(),
+            };
+            peer.outbox.close(Some(last));
+        }
+    }
+
+    /// 大量的数据分批放进队列, 对方收得慢时在这里等, 不会被丢掉
+    pub async fn send_all(&self, addr: SocketAddr, events: impl IntoIterator<Item = Event>) {
+        let outbox = match self.peers.get(&addr) {
+            Some(peer) => peer.outbox.clone(),
+            None => return,
+        };
+        for event in events {
+            outbox.wait_room().await;
+            self.deliver(addr, &outbox, event);
+        }
+    }
+
+    pub async fn send_message(&self, addr: SocketAddr, message: Message) {
+        let message = self.publish(message);
+        self.send_to(addr, Event::Message(message)).await;
+    }
+
+    pub async fn replay(&self, addr: SocketAddr, messages: Vec<Arc<Message>>) {
+        for message in messages {
+            self.send_to(addr, Event::Message(Arc::new(message.replay())))
+                .await;
+        }
+    }
+
+    /// 只有自己能看到的服务端回复
+    pub async fn reply(&self, addr: SocketAddr, content: impl Into<String>) {
+        self.send_message(addr, Message::reply(content)).await;
+    }
+
+    /// id 是出错的那一帧, 按行协议的客户端没有 id
+    pub async fn error(&self, addr: SocketAddr, id: Option<u64>, reason: impl Into<String>) {
+        let reason = reason.into();
+        self.send_to(addr, Event::Error { id, reason }).await;
+    }
+
+    pub fn add(&self, addr: SocketAddr, username: String, transport: Transport) -> Peer {
+        let outbox = Outbox::new(self.config.outbox.clone());
+        self.index_name(&username, addr);
+        self.peers.insert(
+            addr,
+            PeerHandle {
+                username: username.clone(),
+                account: None,
+                presence: Presence::Online,
+                outbox: outbox.clone(),
+                connected_at: Utc::now(),
+            },
+        );
+
+        // receive message from others, and send them to the client
+        let Transport {
+            stream: stream_receiver,
+            sink: mut stream_sender,
+        } = transport;
+        let closed = outbox.closed();
+        let writer_closed = closed.clone();
+        tokio::spawn(async move {
+            while let Some(event) = outbox.pop().await {
+                let result = if writer_closed.is_cancelled() {
+                    // 关闭后的最后一条, 对方不收就算了
+                    match timeout(CLOSE_TIMEOUT, stream_sender.send(event)).await {
+                        Ok(result) => result,
+                        Err(_) => break,
+                    }
+                } else {
+                    // 客户端卡住时 send 会一直等, 被断开时直接放弃
+                    tokio::select! {
+                        result = stream_sender.send(event) => result,
+                        _ = writer_closed.cancelled() => break,
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("Failed to send message to peer {}: {}", addr, e);
+                }
+            }
+        });
+        Peer {
+            username,
+            account: None,
+            session: nanoid!(),
+            room: None,
+            stream: stream_receiver,
+            closed,
+            limiter: RateLimiter::new(&self.config.limits),
+            last_seen: Instant::now(),
+            last_active: Instant::now(),
+            pings: 0,
+            upload: None,
+        }
+    }
+
+    /// 断开连接: 从所有房间退出, 返回退出的房间
+    pub fn remove(&self, addr: SocketAddr) -> Vec<String> {
+        if let Some((_, peer)) = self.peers.remove(&addr) {
+            self.unindex_name(&peer.username, addr);
+            if peer.outbox.dropped() > 0 {
+                info!(
+                    "Peer {} dropped {} messages in total",
+                    addr,
+                    peer.outbox.dropped()
+                );
+            }
+            peer.outbox.close(None);
+        }
+        let rooms = self.rooms_of(addr);
+        for room in &rooms {
+            self.leave(room, addr);
+        }
+        rooms
+    }
+
+    /// 返回 false 表示已经在房间里了
+    pub fn join(&self, room: &str, addr: SocketAddr) -> bool {
+        self.rooms.entry(room.to_string()).or_default().insert(addr)
+    }
+
+    /// 返回 false 表示不在房间里, 房间空了会被删除
+    pub fn leave(&self, room: &str, addr: SocketAddr) -> bool {
+        let left = match self.rooms.get(room) {
+            Some(members) => members.remove(&addr).is_some(),
+            None => false,
+        };
+        self.rooms.remove_if(room, |_, members| members.is_empty());
+        left
+    }
+
+    pub fn rooms_of(&self, addr: SocketAddr) -> Vec<String> {
+        self.rooms
+            .iter()
+            .filter(|members| members.contains(&addr))
+            .map(|members| members.key().clone())
+            .collect()
+    }
+
+    /// 按名字找人 (不区分大小写), 返回多个表示有重名
+    pub fn find_user(&self, username: &str) -> Vec<SocketAddr> {
+        self.names
+            .get(&username.to_lowercase())
+            .map(|addrs| addrs.iter().map(|addr| *addr).collect())
+            .unwrap_or_default()
+    }
+
+    /// 登录了这个账号的所有连接
+    pub fn sessions_of(&self, account: &str) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|peer| {
+                peer.account
+                    .as_ref()
+                    .is_some_and(|a| a.eq_ignore_ascii_case(account))
+            })
+            .map(|peer| *peer.key())
+            .collect()
+    }
+
+    /// 满足条件的连接和名字
+    pub fn select_peers(
+        &self,
+        f: impl Fn(SocketAddr, &PeerHandle) -> bool,
+    ) -> Vec<(SocketAddr, String)> {
+        self.peers
+            .iter()
+            .filter(|peer| f(*peer.key(), peer.value()))
+            .map(|peer| (*peer.key(), peer.username.clone()))
+            .collect()
+    }
+
+    pub fn is_operator(&self, account: Option<&str>) -> bool {
+        account.is_some_and(|account| self.config.moderation.is_operator(account))
+    }
+
+    /// 过期的禁言顺便清掉
+    pub fn is_muted(&self, username: &str) -> bool {
+        let key = username.to_lowercase();
+        let now = Utc::now();
+        self.mutes
+            .remove_if(&key, |_, until| until.is_some_and(|at| at <= now));
+        self.mutes.contains_key(&key)
+    }
+
+    pub fn presence(&self, addr: SocketAddr) -> Option<Presence> {
+        self.peers.get(&addr).map(|peer| peer.presence.clone())
+    }
+
+    /// 状态真的变了才通知房间里的人
+    pub async fn set_presence(&self, addr: SocketAddr, presence: Presence) {
+        let username = match self.peers.get_mut(&addr) {
+            Some(mut peer) if peer.presence != presence => {
+                peer.presence = presence.clone();
+                peer.username.clone()
+            }
+            _ => return,
+        };
+        self.broadcast_system(SystemMessage::Presence {
+            addr,
+            username,
+            presence,
+        })
+        .await;
+    }
+
+    pub fn topic(&self, room: &str) -> Option<String> {
+        self.topics.get(room).map(|topic| topic.clone())
+    }
+
+    /// 发到这些人所在的所有房间, 每个房间只发一次
+    async fn notify_rooms(&self, addrs: &[SocketAddr], content: &str) {
+        let rooms: BTreeSet<String> = addrs.iter().flat_map(|addr| self.rooms_of(*addr)).collect();
+        for room in rooms {
+            self.broadcast(&room, Message::system(&room, content)).await;
+        }
+    }
+
+    pub fn set_account(&self, addr: SocketAddr, account: Option<String>) {
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            peer.account = account;
+        }
+    }
+
+    fn index_name(&self, username: &str, addr: SocketAddr) {
+        self.names
+            .entry(username.to_lowercase())
+            .or_default()
+            .insert(addr);
+    }
+
+    fn unindex_name(&self, username: &str, addr: SocketAddr) {
+        let key = username.to_lowercase();
+        if let Some(addrs) = self.names.get(&key) {
+            addrs.remove(&addr);
+        }
+        self.names.remove_if(&key, |_, addrs| addrs.is_empty());
+    }
+
+    /// 房间里所有人的名字和状态, 房间不存在时返回 None
+    pub fn members(&self, room: &str) -> Option<Vec<(String, Presence)>> {
+        let members = self.rooms.get(room)?;
+        let mut names: Vec<(String, Presence)> = members
+            .iter()
+            .filter_map(|addr| {
+                self.peers
+                    .get(addr.key())
+                    .map(|p| (p.username.clone(), p.presence.clone()))
+            })
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        Some(names)
+    }
+
+    /// (房间名, 人数), 按房间名排序
+    pub fn room_list(&self) -> Vec<(String, usize)> {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .iter()
+            .map(|members| (members.key().clone(), members.len()))
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    pub async fn broadcast_system(&self, system_message: SystemMessage) {
+        match system_message {
+            SystemMessage::UserJoin {
+                addr,
+                room,
+                username,
+            } => {
+                let backlog = self.history.recent(&room, self.history.config().replay);
+                if !self.join(&room, addr) {
+                    return;
+                }
+                // 先把历史消息和 topic 发给新加入的人, 再广播
+                self.replay(addr, backlog).await;
+                if let Some(topic) = self.topic(&room) {
+                    self.reply(addr, format!("Topic for {}: {}", room, topic))
+                        .await;
+                }
+                // state 广播数据
+                self.broadcast(&room, Message::join(&room, username)).await;
+            }
+            SystemMessage::UserLeave {
+                addr,
+                room,
+                username,
+            } => {
+                // 先退出再广播, 自己不会收到
+                self.leave(&room, addr);
+                self.broadcast(&room, Message::leave(&room, username)).await;
+            }
+            SystemMessage::Rename { addr, from, to } => {
+                if let Some(mut peer) = self.peers.get_mut(&addr) {
+                    peer.username = to.clone();
+                }
+                self.unindex_name(&from, addr);
+                self.index_name(&to, addr);
+                // 改名不能躲过禁言
+                if let Some((_, until)) = self.mutes.remove(&from.to_lowercase()) {
+                    self.mutes.insert(to.to_lowercase(), until);
+                }
+                for room in self.rooms_of(addr) {
+                    let message =
+                        Message::system(&room, format!("{} is now known as {}", from, to));
+                    self.broadcast(&room, message).await;
+                }
+            }
+            SystemMessage::Presence {
+                addr,
+                username,
+                presence,
+            } => {
+                let notice = match presence {
+                    Presence::Online => format!("{} is back", username),
+                    Presence::Away(Some(message)) => format!("{} is away: {}", username, message),
+                    Presence::Away(None) => format!("{} is away", username),
+                    Presence::Idle => format!("{} is idle", username),
+                };
+                self.notify_rooms(&[addr], &notice).await;
+            }
+            SystemMessage::Kick {
+                addr,
+                username,
+                by,
+                reason,
+            } => {
+                let reason = reason.map(|r| format!(": {}", r)).unwrap_or_default();
+                let notice = format!("{} was kicked by {}{}", username, by, reason);
+                self.notify_rooms(&[addr], &notice).await;
+                self.disconnect(addr, &format!("You were kicked by {}{}", by, reason));
+            }
+            SystemMessage::Ban {
+                addr,
+                username,
+                ban,
+            } => {
+                let reason = ban
+                    .reason
+                    .as_ref()
+                    .map(|r| format!(": {}", r))
+                    .unwrap_or_default();
+                let notice = format!(
+                    "{} was banned by {}{}{}",
+                    username,
+                    ban.by,
+                    ban::until(ban.expires_at),
+                    reason
+                );
+                self.notify_rooms(&[addr], &notice).await;
+                self.disconnect(addr, &ban.describe());
+            }
+            SystemMessage::Mute {
+                username,
+                by,
+                expires_at,
+            } => {
+                self.mutes.insert(username.to_lowercase(), expires_at);
+                let notice = format!("{} was muted by {}{}", username, by, ban::until(expires_at));
+                self.notify_rooms(&self.find_user(&username), &notice).await;
+            }
+            SystemMessage::Unmute { username, by } => {
+                self.mutes.remove(&username.to_lowercase());
+                let notice = format!("{} was unmuted by {}", username, by);
+                self.notify_rooms(&self.find_user(&username), &notice).await;
+            }
+            SystemMessage::Topic { room, by, topic } => {
+                let notice = format!("{} changed the topic to: {}", by, topic);
+                self.topics.insert(room.clone(), topic);
+                self.broadcast(&room, Message::system(&room, notice)).await;
+            }
+            SystemMessage::Announce { by, text } => {
+                // 每个人只收一次, 不管在几个房间
+                let addrs: Vec<SocketAddr> = self.peers.iter().map(|peer| *peer.key()).collect();
+                let notice = format!("Announcement from {}: {}", by, text);
+                for addr in addrs {
+                    self.reply(addr, notice.as_str()).await;
+                }
+            }
+        }
+    }
+}
+
+impl Peer {
+    /// 现在发的消息记在谁名下
+    pub fn author(&self) -> String {
+        match &self.account {
+            Some(account) => format!("account:{}", account.to_lowercase()),
+            None => format!("session:{}", self.session),
+        }
+    }
+
+    /// 登录之前以游客身份发的也算自己的
+    pub fn wrote(&self, message: &Message) -> bool {
+        message.author.as_ref().is_some_and(|author| {
+            *author == self.author() || *author == format!("session:{}", self.session)
+        })
+    }
+}
+
+impl Display for Presence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Away(Some(message)) => write!(f, "away: {}", message),
+            Presence::Away(None) => write!(f, "away"),
+            Presence::Idle => write!(f, "idle"),
+        }
+    }
+}
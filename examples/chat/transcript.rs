@@ -0,0 +1,180 @@
+use crate::message::Message;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// 聊天记录, 每天一个 jsonl 文件, 只追加不修改
+#[derive(Debug)]
+pub struct Transcript {
+    sender: mpsc::UnboundedSender<Job>,
+}
+
+#[derive(Debug)]
+enum Job {
+    Append(Arc<Message>),
+    // 前面的都写完并且 flush 之后回复
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Json,
+    Text,
+}
+
+impl Transcript {
+    /// 启动后台写文件的 task, 不阻塞 broadcast
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+        tokio::spawn(async move {
+            let mut segment: Option<(NaiveDate, BufWriter<File>)> = None;
+            while let Some(job) = rx.recv().await {
+                let done = match job {
+                    Job::Append(message) => {
+                        if let Err(e) = append(&dir, &mut segment, &message).await {
+                            warn!("Failed to write transcript: {}", e);
+                            continue;
+                        }
+                        None
+                    }
+                    Job::Flush(done) => Some(done),
+                };
+                // 队列空了再 flush, 消息多的时候不用每条都写盘
+                if done.is_none() && !rx.is_empty() {
+                    continue;
+                }
+                let flushed = match segment.as_mut() {
+                    Some((_, file)) => file.flush().await,
+                    None => Ok(()),
+                };
+                if let Err(e) = flushed {
+                    warn!("Failed to flush transcript: {}", e);
+                }
+                if let Some(done) = done {
+                    let _ = done.send(());
+                }
+            }
+        });
+        Ok(Self { sender: tx })
+    }
+
+    pub fn append(&self, message: Arc<Message>) {
+        if let Err(e) = self.sender.send(Job::Append(message)) {
+            warn!("Transcript writer is gone: {}", e);
+        }
+    }
+
+    /// 等已经提交的记录都写到文件里, 退出前调用
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.sender.send(Job::Flush(tx)).is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+async fn append(
+    dir: &Path,
+    segment: &mut Option<(NaiveDate, BufWriter<File>)>,
+    message: &Message,
+) -> Result<()> {
+    let date = message.timestamp.date_naive();
+    if segment.as_ref().is_none_or(|(day, _)| *day != date) {
+        if let Some((_, mut file)) = segment.take() {
+            file.flush().await?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(dir, date))
+            .await?;
+        *segment = Some((date, BufWriter::new(file)));
+    }
+    let (_, file) = segment.as_mut().expect("segment is opened above");
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+fn segment_path(dir: &Path, date: NaiveDate) -> PathBuf {
+    dir.join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+}
+
+/// 读取 [from, to] 之间的消息, 按文件顺序 (也就是时间顺序) 返回
+pub fn load(dir: &Path, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Message>> {
+    let mut messages = vec![];
+    let mut date = from.date_naive();
+    while date <= to.date_naive() {
+        let path = segment_path(dir, date);
+        date = date
+            .succ_opt()
+            .ok_or_else(|| anyhow!("date out of range"))?;
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            // 进程被杀掉时最后一行可能不完整, 跳过
+            let message: Message = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Skip bad line {} in {}: {}", i + 1, path.display(), e);
+                    continue;
+                }
+            };
+            if message.timestamp >= from && message.timestamp <= to {
+                messages.push(message);
+            }
+        }
+    }
+    Ok(messages)
+}
+
+pub fn export(
+    dir: &Path,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    format: ExportFormat,
+    mut writer: impl Write,
+) -> Result<()> {
+    let messages = load(dir, from, to)?;
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &messages)?;
+            writeln!(writer)?;
+        }
+        ExportFormat::Text => {
+            for message in messages {
+                writeln!(writer, "{}", message.replay())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 支持 RFC3339 或者 `YYYY-MM-DD`, 日期作为结束时间时取当天最后一刻
+pub fn parse_time(s: &str, end_of_day: bool) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| anyhow!("invalid time {}, expect RFC3339 or YYYY-MM-DD", s))?;
+    let time = if end_of_day {
+        NaiveTime::from_hms_nano_opt(23, 59, 59, 999_999_999).expect("valid time")
+    } else {
+        NaiveTime::MIN
+    };
+    Ok(date.and_time(time).and_utc())
+}
@@ -0,0 +1,297 @@
+use crate::config::LimitConfig;
+use crate::message::Event;
+use anyhow::{anyhow, Result};
+use axum::extract::ws::{Message as WsMessage, WebSocket};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::{Bytes, BytesMut};
+use ecosystem::chat::{ClientFrame, FrameCodec, Message, ServerFrame, PROTOCOL_VERSION};
+use futures::stream;
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use std::pin::Pin;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, LinesCodec, LinesCodecError};
+
+/// 客户端发来的一行, 结构化协议会带上帧 id, 处理完要回 Ack
+#[derive(Debug)]
+pub struct Inbound {
+    pub id: Option<u64>,
+    pub line: String,
+}
+
+/// 除了一行一行的输入, 还有客户端对心跳的回应和上传的文件内容
+#[derive(Debug)]
+pub enum Incoming {
+    Line(Inbound),
+    Pong(u64),
+    // 按行协议是 `/chunk <base64>`, 结构化协议是原始字节
+    Chunk { id: Option<u64>, data: Bytes },
+}
+
+/// 超长的一行会被丢掉, 回复错误之后连接还能继续用
+#[derive(Debug, Error)]
+#[error("Message too long, at most {limit} bytes")]
+pub struct LineTooLong {
+    pub id: Option<u64>,
+    pub limit: usize,
+}
+
+/// `/chunk` 后面不是 base64, 回复错误之后连接还能继续用
+#[derive(Debug, Error)]
+#[error("Invalid chunk, expect /chunk <base64>")]
+pub struct InvalidChunk {
+    pub id: Option<u64>,
+}
+
+// Sync 是因为 Peer 会被 &Context 借用着跨 await
+pub type InboundStream = Pin<Box<dyn Stream<Item = Result<Incoming>> + Send + Sync>>;
+pub type EventSink = Pin<Box<dyn Sink<Event, Error = anyhow::Error> + Send>>;
+
+/// TCP 按行, WebSocket 按行, TCP 结构化协议的客户端在 State 里看起来都一样
+pub struct Transport {
+    pub stream: InboundStream,
+    pub sink: EventSink,
+}
+
+/// 明文的 TcpStream 或者 TLS 之后的流
+pub trait Io: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static {}
+
+impl<T> Io for T where T: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static {}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Protocol {
+    Lines,
+    Framed,
+}
+
+impl Transport {
+    pub async fn accept(stream: impl Io, protocol: Protocol, limits: &LimitConfig) -> Result<Self> {
+        match protocol {
+            Protocol::Lines => Ok(Self::lines(stream, limits)),
+            Protocol::Framed => Self::framed(stream, limits).await,
+        }
+    }
+
+    pub fn lines(stream: impl Io, limits: &LimitConfig) -> Self {
+        let codec = BoundedLines::new(limits.max_line_len);
+        let (sink, stream) = Framed::new(stream, codec).split();
+        let sink = sink
+            .with_flat_map(|event: Event| stream::iter(render_line(event).map(Ok)))
+            .sink_map_err(anyhow::Error::from);
+        Self {
+            stream: Box::pin(stream.map(|line| parse_line(line??))),
+            sink: Box::pin(sink),
+        }
+    }
+
+    /// 一个 text frame 里有多行时拆开, 和 TCP 客户端保持一致
+    pub fn websocket(socket: WebSocket, limits: &LimitConfig) -> Self {
+        let limit = limits.max_line_len;
+        let (sink, stream) = socket.split();
+        let stream = stream
+            .take_while(|message| future::ready(!matches!(message, Ok(WsMessage::Close(_)))))
+            .flat_map(move |message| {
+                let lines: Vec<Result<Incoming>> = match message {
+                    Ok(WsMessage::Text(text)) => text
+                        .lines()
+                        .map(|line| {
+                            if line.len() > limit {
+                                return Err(LineTooLong { id: None, limit }.into());
+                            }
+                            parse_line(line.to_string())
+                        })
+                        .collect(),
+                    // 服务端的心跳用 WebSocket 自己的 ping, 浏览器会自动回 pong
+                    Ok(WsMessage::Pong(payload)) => {
+                        let nonce = <[u8; 8]>::try_from(payload.as_ref())
+                            .map(u64::from_be_bytes)
+                            .unwrap_or_default();
+                        vec![Ok(Incoming::Pong(nonce))]
+                    }
+                    // 客户端发来的 ping axum 会自动回
+                    Ok(_) => vec![],
+                    Err(e) => vec![Err(e.into())],
+                };
+                stream::iter(lines)
+            });
+        let sink = sink
+            .with_flat_map(|event: Event| {
+                let message = match event {
+                    Event::Ping(nonce) => {
+                        Some(WsMessage::Ping(nonce.to_be_bytes().to_vec().into()))
+                    }
+                    event => render_line(event).map(|line| WsMessage::Text(line.into())),
+                };
+                stream::iter(message.map(Ok))
+            })
+            .sink_map_err(anyhow::Error::from);
+        Self {
+            stream: Box::pin(stream),
+            sink: Box::pin(sink),
+        }
+    }
+
+    /// 先握手: 客户端发 Hello, 版本一致时回 Welcome
+    pub async fn framed(stream: impl Io, limits: &LimitConfig) -> Result<Self> {
+        let codec = FrameCodec::<ClientFrame, ServerFrame>::with_max_length(limits.max_frame_len);
+        let mut framed = Framed::new(stream, codec);
+        let reason = match framed.next().await {
+            Some(Ok(ClientFrame::Hello { version })) if version == PROTOCOL_VERSION => None,
+            Some(Ok(ClientFrame::Hello { version })) => Some(format!(
+                "Unsupported protocol version {}, server speaks {}",
+                version, PROTOCOL_VERSION
+            )),
+            Some(Ok(_)) => Some("Expect hello as the first frame".to_string()),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(anyhow!("Connection closed before hello")),
+        };
+        if let Some(reason) = reason {
+            framed
+                .send(ServerFrame::Error {
+                    id: None,
+                    reason: reason.clone(),
+                })
+                .await?;
+            return Err(anyhow!(reason));
+        }
+        framed
+            .send(ServerFrame::Welcome {
+                version: PROTOCOL_VERSION,
+            })
+            .await?;
+
+        let (sink, stream) = framed.split();
+        let limit = limits.max_line_len;
+        let stream = stream.map(move |frame| match frame? {
+            ClientFrame::Send { id, line } if line.len() > limit => Err(LineTooLong {
+                id: Some(id),
+                limit,
+            }
+            .into()),
+            ClientFrame::Send { id, line } => Ok(Incoming::Line(Inbound { id: Some(id), line })),
+            ClientFrame::Pong { nonce } => Ok(Incoming::Pong(nonce)),
+            ClientFrame::Chunk { id, data, .. } => Ok(Incoming::Chunk { id: Some(id), data }),
+            ClientFrame::Hello { .. } => Err(anyhow!("Unexpected hello after handshake")),
+        });
+        let sink = sink
+            .with(|event: Event| future::ready(Ok::<_, ecosystem::Error>(render_frame(event))))
+            .sink_map_err(anyhow::Error::from);
+        Ok(Self {
+            stream: Box::pin(stream),
+            sink: Box::pin(sink),
+        })
+    }
+
+    pub async fn send(&mut self, event: Event) -> Result<()> {
+        self.sink.send(event).await
+    }
+
+    /// 下一行输入, 心跳回应和文件内容直接跳过
+    pub async fn next(&mut self) -> Result<Inbound> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Incoming::Line(inbound))) => return Ok(inbound),
+                Some(Ok(Incoming::Pong(_) | Incoming::Chunk { .. })) => continue,
+                Some(Err(e)) => return Err(e),
+                None => return Err(anyhow!("No message received")),
+            }
+        }
+    }
+}
+
+/// 按行协议用 `/pong <nonce>` 回应 `PING <nonce>`, 用 `/chunk <base64>` 上传文件
+fn parse_line(line: String) -> Result<Incoming> {
+    if let Some(nonce) = line
+        .strip_prefix("/pong ")
+        .and_then(|nonce| nonce.trim().parse().ok())
+    {
+        return Ok(Incoming::Pong(nonce));
+    }
+    match line.strip_prefix("/chunk ") {
+        Some(chunk) => match BASE64_STANDARD.decode(chunk.trim()) {
+            Ok(data) => Ok(Incoming::Chunk {
+                id: None,
+                data: Bytes::from(data),
+            }),
+            Err(_) => Err(InvalidChunk { id: None }.into()),
+        },
+        None => Ok(Incoming::Line(Inbound { id: None, line })),
+    }
+}
+
+/// LinesCodec 遇到超长的行会报错, 而 Framed 报错之后就不再读了,
+/// 这里把超长的行变成一个 Err item, 剩下的部分由 LinesCodec 自己丢掉
+struct BoundedLines {
+    inner: LinesCodec,
+    limit: usize,
+}
+
+impl BoundedLines {
+    fn new(limit: usize) -> Self {
+        Self {
+            inner: LinesCodec::new_with_max_length(limit),
+            limit,
+        }
+    }
+
+    fn check(
+        &self,
+        result: Result<Option<String>, LinesCodecError>,
+    ) -> Result<Option<Result<String, LineTooLong>>, LinesCodecError> {
+        match result {
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(Err(LineTooLong {
+                id: None,
+                limit: self.limit,
+            }))),
+            result => result.map(|line| line.map(Ok)),
+        }
+    }
+}
+
+impl Decoder for BoundedLines {
+    type Item = Result<String, LineTooLong>;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.inner.decode(src);
+        self.check(result)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.inner.decode_eof(src);
+        self.check(result)
+    }
+}
+
+impl Encoder<String> for BoundedLines {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: String, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.inner.encode(line, dst)
+    }
+}
+
+/// 按行协议没有 Ack, 出错时和普通回复一样显示
+fn render_line(event: Event) -> Option<String> {
+    match event {
+        Event::Message(message) => Some(message.to_string()),
+        Event::Prompt(text) => Some(text),
+        Event::Error { reason, .. } => Some(Message::reply(reason).to_string()),
+        Event::Ack(_) => None,
+        Event::Ping(nonce) => Some(format!("PING {}", nonce)),
+        Event::File { hash, name, size } => Some(format!("FILE {} {} {}", hash, size, name)),
+        Event::Chunk(data) => Some(format!("CHUNK {}", BASE64_STANDARD.encode(data))),
+    }
+}
+
+fn render_frame(event: Event) -> ServerFrame {
+    match event {
+        Event::Message(message) => ServerFrame::from(message.as_ref()),
+        Event::Prompt(text) => ServerFrame::Prompt { text },
+        Event::Error { id, reason } => ServerFrame::Error { id, reason },
+        Event::Ack(id) => ServerFrame::Ack { id },
+        Event::Ping(nonce) => ServerFrame::Ping { nonce },
+        Event::File { hash, name, size } => ServerFrame::File { hash, name, size },
+        Event::Chunk(data) => ServerFrame::chunk(data),
+    }
+}
@@ -0,0 +1,89 @@
+use crate::command::Registry;
+use crate::handle_client;
+use crate::state::State;
+use crate::transport::Transport;
+use axum::extract::connect_info::Connected;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{ConnectInfo, State as AxumState};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::serve::IncomingStream;
+use axum::Router;
+use ecosystem::tls::{TlsAcceptor, TlsListener};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+#[derive(Clone)]
+struct WsState {
+    state: Arc<State>,
+    registry: Arc<Registry>,
+}
+
+/// 明文和 TLS 的 listener 都能拿到客户端地址
+#[derive(Debug, Clone, Copy)]
+struct ClientAddr(SocketAddr);
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    tls: Option<Arc<TlsAcceptor>>,
+    app: Router,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let app = app.into_make_service_with_connect_info::<ClientAddr>();
+    // 只等还没升级成 WebSocket 的请求, 升级之后的连接由 State::shutdown 通知
+    match tls {
+        Some(acceptor) => {
+            axum::serve(TlsListener::new(listener, acceptor)?, app)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+        }
+        None => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+        }
+    }
+}
+
+/// 浏览器连 `/ws`, 和 TCP 客户端共用同一个 State
+pub fn router(state: Arc<State>, registry: Arc<Registry>) -> Router {
+    Router::new()
+        .route("/ws", get(ws_handler))
+        .with_state(WsState { state, registry })
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(ClientAddr(addr)): ConnectInfo<ClientAddr>,
+    AxumState(ws_state): AxumState<WsState>,
+) -> impl IntoResponse {
+    info!("Got websocket connection from: {}", addr);
+    let limits = ws_state.state.config().limits.clone();
+    ws.max_message_size(limits.max_frame_len)
+        .on_upgrade(move |socket| {
+            let clients = ws_state.state.clients.clone();
+            clients.track_future(async move {
+                let transport = Transport::websocket(socket, &limits);
+                if let Err(e) =
+                    handle_client(ws_state.state, ws_state.registry, addr, transport).await
+                {
+                    warn!("Client error: {}", e);
+                }
+            })
+        })
+}
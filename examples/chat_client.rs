@@ -0,0 +1,782 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use chrono::Local;
+use ecosystem::chat::{
+    ClientFrame, FrameCodec, Message, MessageKind, ServerFrame, PROTOCOL_VERSION,
+};
+use futures::{SinkExt, StreamExt};
+use rustyline::error::ReadlineError;
+use rustyline::{DefaultEditor, ExternalPrinter};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::{BufRead, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tokio_util::codec::Framed;
+
+// 连接的是服务端的结构化协议端口
+const DEFAULT_ADDR: &str = "127.0.0.1:8082";
+// 服务端登录后自动加入的房间
+const DEFAULT_ROOM: &str = "lobby";
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// 本地最多保留多少行
+const SCROLLBACK: usize = 1000;
+// 上传时每帧多少字节, 要小于服务端的 max_frame_len
+const UPLOAD_CHUNK: usize = 8 * 1024;
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const MAGENTA: &str = "\x1b[35m";
+const MENTION: &str = "\x1b[1;33m";
+
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Io for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+type Connection = Framed<Box<dyn Io>, FrameCodec<ServerFrame, ClientFrame>>;
+
+/// cargo run --example chat_client -- [addr] [username]
+struct Options {
+    addr: String,
+    username: Option<String>,
+    // CHAT_CLIENT_TLS_CA 指定了服务端的 CA 时走 TLS
+    tls: Option<TlsConnector>,
+    color: bool,
+    // 下载的文件存到哪里, CHAT_CLIENT_DOWNLOAD_DIR, 默认当前目录
+    download_dir: PathBuf,
+}
+
+enum Input {
+    Line(String),
+    Quit,
+}
+
+/// 断线重连后要恢复的东西
+#[derive(Debug, Default)]
+struct Session {
+    username: Option<String>,
+    // (账号, 密码), /login 或者 /register 成功之后记下来, 重连时自动登录
+    account: Option<(String, String)>,
+    // 加入的房间和当前说话的房间, 从服务端发来的自己的 join/leave 里得到
+    rooms: Vec<String>,
+    room: Option<String>,
+    // 见过的最大消息 id, 重连后服务端回放的历史里见过的不再显示
+    last_id: u64,
+    // 最近的消息, 收到编辑, 删除和 reaction 时更新
+    messages: BTreeMap<u64, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    message: Message,
+    // emoji -> 谁点的
+    reactions: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// 屏幕输出, 同时留一份在本地的 scrollback 里
+struct Output {
+    // 终端里用 rustyline 的 printer, 输出不会把正在输入的行打乱
+    printer: Option<Box<dyn ExternalPrinter + Send>>,
+    scrollback: VecDeque<String>,
+    color: bool,
+}
+
+/// 发出去还没收到 Ack 的行, 收到 Ack 时没有出错才生效
+#[derive(Debug)]
+enum Sent {
+    Line(String),
+    // 回答 `Password for xxx:` 的密码
+    Password(String),
+    // 客户端自己发的, 比如重连时自动登录
+    Auto,
+    // `/upload <size> <name>`, 服务端确认之后再发文件内容
+    Upload(Bytes),
+}
+
+struct Client {
+    options: Options,
+    session: Session,
+    output: Output,
+}
+
+/// 每条连接自己的状态
+#[derive(Default)]
+struct Link {
+    next_id: u64,
+    pending: HashMap<u64, (Sent, bool)>,
+    logged_in: bool,
+    // 自动登录只试一次, 失败后交给用户
+    auto_login: bool,
+    awaiting_password: bool,
+    // 正在下载的文件
+    download: Option<Download>,
+}
+
+struct Download {
+    hash: String,
+    name: String,
+    size: u64,
+    data: Vec<u8>,
+}
+
+enum Exit {
+    Quit,
+    Closed,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::from_env()?;
+    let (sender, mut input) = mpsc::unbounded_channel();
+    let printer = spawn_input(sender)?;
+    let mut client = Client {
+        session: Session {
+            username: options.username.clone(),
+            ..Session::default()
+        },
+        output: Output {
+            printer,
+            scrollback: VecDeque::new(),
+            color: options.color,
+        },
+        options,
+    };
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        let result = match client.connect().await {
+            Ok(connection) => client.run(connection, &mut input).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(Exit::Quit) => return Ok(()),
+            Ok(Exit::Closed) => client.output.error("Connection closed by server"),
+            Err(e) => client.output.error(&format!("Disconnected: {:#}", e)),
+        }
+        // 连上过一段时间才算稳定, 重新从最短的间隔开始
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+        client
+            .output
+            .system(&format!("Reconnecting in {}s...", backoff.as_secs()));
+        // 等的时候也要能 /quit
+        let wait = sleep(backoff);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                line = input.recv() => match line {
+                    Some(Input::Line(line)) if line.trim() == "/quit" => return Ok(()),
+                    Some(Input::Line(line)) => {
+                        if !client.local_command(&line) {
+                            client.output.error("Not connected, message not sent");
+                        }
+                    }
+                    Some(Input::Quit) | None => return Ok(()),
+                },
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+impl Options {
+    fn from_env() -> Result<Self> {
+        let mut args = std::env::args().skip(1);
+        let addr = args.next().unwrap_or_else(|| DEFAULT_ADDR.to_string());
+        let username = args.next();
+        let tls = match std::env::var("CHAT_CLIENT_TLS_CA") {
+            Ok(ca) => {
+                let cert = std::env::var("CHAT_CLIENT_TLS_CERT").ok();
+                let key = std::env::var("CHAT_CLIENT_TLS_KEY").ok();
+                let identity = match (&cert, &key) {
+                    (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+                    (None, None) => None,
+                    _ => {
+                        return Err(anyhow!(
+                            "CHAT_CLIENT_TLS_CERT and CHAT_CLIENT_TLS_KEY must be set together"
+                        ))
+                    }
+                };
+                Some(ecosystem::tls::connector(Path::new(&ca), identity)?)
+            }
+            Err(_) => None,
+        };
+        let color = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+        let download_dir = std::env::var_os("CHAT_CLIENT_DOWNLOAD_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."));
+        Ok(Self {
+            addr,
+            username,
+            tls,
+            color,
+            download_dir,
+        })
+    }
+}
+
+/// 读输入放在单独的线程里, 终端里用 rustyline 支持行编辑和历史
+fn spawn_input(
+    sender: mpsc::UnboundedSender<Input>,
+) -> Result<Option<Box<dyn ExternalPrinter + Send>>> {
+    if !std::io::stdin().is_terminal() {
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(Input::Line(line)).is_err() {
+                    return;
+                }
+            }
+            let _ = sender.send(Input::Quit);
+        });
+        return Ok(None);
+    }
+    let mut editor = DefaultEditor::new()?;
+    let printer = editor.create_external_printer()?;
+    std::thread::spawn(move || loop {
+        match editor.readline("> ") {
+            Ok(line) if line.trim().is_empty() => {}
+            Ok(line) => {
+                let _ = editor.add_history_entry(line.as_str());
+                if sender.send(Input::Line(line)).is_err() {
+                    return;
+                }
+            }
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) | Err(_) => {
+                let _ = sender.send(Input::Quit);
+                return;
+            }
+        }
+    });
+    Ok(Some(Box::new(printer)))
+}
+
+impl Client {
+    async fn connect(&mut self) -> Result<Connection> {
+        self.output
+            .system(&format!("Connecting to {}...", self.options.addr));
+        let stream = TcpStream::connect(&self.options.addr).await?;
+        let stream: Box<dyn Io> = match &self.options.tls {
+            Some(connector) => {
+                let host = match self.options.addr.rsplit_once(':') {
+                    Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+                    None => self.options.addr.as_str(),
+                };
+                let name = ServerName::try_from(host.to_string())?;
+                Box::new(connector.connect(name, stream).await?)
+            }
+            None => Box::new(stream),
+        };
+        let mut connection = Framed::new(stream, FrameCodec::new());
+        connection
+            .send(ClientFrame::Hello {
+                version: PROTOCOL_VERSION,
+            })
+            .await?;
+        match connection.next().await {
+            Some(Ok(ServerFrame::Welcome { .. })) => Ok(connection),
+            Some(Ok(ServerFrame::Error { reason, .. })) => Err(anyhow!(reason)),
+            Some(Ok(frame)) => Err(anyhow!("Expect welcome, got {:?}", frame)),
+            Some(Err(e)) => Err(e.into()),
+            None => Err(anyhow!("Connection closed during handshake")),
+        }
+    }
+
+    async fn run(
+        &mut self,
+        mut connection: Connection,
+        input: &mut mpsc::UnboundedReceiver<Input>,
+    ) -> Result<Exit> {
+        self.output.system("Connected");
+        let mut link = Link {
+            auto_login: self.session.username.is_some(),
+            ..Link::default()
+        };
+        loop {
+            tokio::select! {
+                frame = connection.next() => match frame {
+                    Some(frame) => self.handle_frame(&mut connection, &mut link, frame?).await?,
+                    None => return Ok(Exit::Closed),
+                },
+                line = input.recv() => match line {
+                    Some(Input::Line(line)) => {
+                        if self.local_command(&line) {
+                            continue;
+                        }
+                        if let Some(path) = line.trim().strip_prefix("/upload ") {
+                            self.upload(&mut connection, &mut link, path.trim()).await?;
+                            continue;
+                        }
+                        let quit = line.trim() == "/quit";
+                        let sent = match link.awaiting_password {
+                            true => Sent::Password(line.trim().to_string()),
+                            false => Sent::Line(line.clone()),
+                        };
+                        link.awaiting_password = false;
+                        send(&mut connection, &mut link, line, sent).await?;
+                        if quit {
+                            return Ok(Exit::Quit);
+                        }
+                    }
+                    Some(Input::Quit) | None => {
+                        send(&mut connection, &mut link, "/quit".to_string(), Sent::Auto).await?;
+                        return Ok(Exit::Quit);
+                    }
+                },
+            }
+        }
+    }
+
+    async fn handle_frame(
+        &mut self,
+        connection: &mut Connection,
+        link: &mut Link,
+        frame: ServerFrame,
+    ) -> Result<()> {
+        match frame {
+            ServerFrame::Welcome { .. } => {}
+            ServerFrame::Prompt { text } => self.prompt(connection, link, text).await?,
+            ServerFrame::Message(message) => self.show(&message),
+            ServerFrame::Join {
+                id,
+                room,
+                username,
+                timestamp,
+                replayed,
+            } => {
+                let mut message = Message::join(&room, &username);
+                (message.id, message.timestamp, message.replayed) = (id, timestamp, replayed);
+                // 自动登录时服务端先用账号名, restore 里才改回原来的名字
+                let me = self.is_me(&username)
+                    || !link.logged_in
+                        && (self.session.account.as_ref())
+                            .is_some_and(|(account, _)| account.eq_ignore_ascii_case(&username));
+                if !replayed && me {
+                    // 登录成功后服务端会让我们加入默认房间
+                    if !link.logged_in {
+                        link.logged_in = true;
+                        self.restore(connection, link).await?;
+                    }
+                    self.session.rooms.retain(|r| r != &room);
+                    self.session.rooms.push(room.clone());
+                    self.session.room = Some(room);
+                }
+                self.show(&message);
+            }
+            ServerFrame::Leave {
+                id,
+                room,
+                username,
+                timestamp,
+                replayed,
+            } => {
+                let mut message = Message::leave(&room, &username);
+                (message.id, message.timestamp, message.replayed) = (id, timestamp, replayed);
+                if !replayed && self.is_me(&username) {
+                    self.session.rooms.retain(|r| r != &room);
+                    if self.session.room.as_ref() == Some(&room) {
+                        self.session.room = self.session.rooms.first().cloned();
+                    }
+                }
+                self.show(&message);
+            }
+            ServerFrame::Error { id, reason } => {
+                if let Some((_, failed)) = id.and_then(|id| link.pending.get_mut(&id)) {
+                    *failed = true;
+                }
+                self.output.error(&reason);
+            }
+            ServerFrame::Ack { id } => match link.pending.remove(&id) {
+                Some((Sent::Upload(data), false)) => send_chunks(connection, link, data).await?,
+                Some((sent, false)) => self.applied(link, sent),
+                _ => {}
+            },
+            ServerFrame::Ping { nonce } => connection.send(ClientFrame::Pong { nonce }).await?,
+            ServerFrame::File { hash, name, size } => {
+                self.output
+                    .system(&format!("Downloading {} ({} bytes)", name, size));
+                link.download = Some(Download {
+                    hash,
+                    name,
+                    size,
+                    data: Vec::new(),
+                });
+            }
+            ServerFrame::Chunk { data, .. } => {
+                let Some(mut download) = link.download.take() else {
+                    return Ok(());
+                };
+                download.data.extend_from_slice(&data);
+                match download.data.len() as u64 >= download.size {
+                    true => self.save(download).await,
+                    false => link.download = Some(download),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `/upload <path>`: 先告诉服务端文件名和大小, 收到 Ack 之后再发内容
+    async fn upload(
+        &mut self,
+        connection: &mut Connection,
+        link: &mut Link,
+        path: &str,
+    ) -> Result<()> {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) => {
+                self.output
+                    .error(&format!("Failed to read {}: {}", path, e));
+                return Ok(());
+            }
+        };
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string());
+        let line = format!("/upload {} {}", data.len(), name);
+        send(connection, link, line, Sent::Upload(Bytes::from(data))).await
+    }
+
+    /// 校验 hash 之后存到下载目录, 不覆盖已有的文件
+    async fn save(&mut self, download: Download) {
+        let hash = blake3::hash(&download.data).to_hex().to_string();
+        if download.data.len() as u64 != download.size || hash != download.hash {
+            self.output
+                .error(&format!("Download of {} is corrupted", download.name));
+            return;
+        }
+        // 只要文件名, 不能写到下载目录外面
+        let name = Path::new(&download.name)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| hash.clone());
+        let mut path = self.options.download_dir.join(&name);
+        if path.exists() {
+            path = self
+                .options
+                .download_dir
+                .join(format!("{}-{}", &hash[..8], name));
+        }
+        match tokio::fs::write(&path, &download.data).await {
+            Ok(()) => self
+                .output
+                .system(&format!("Saved {} to {}", download.name, path.display())),
+            Err(e) => self
+                .output
+                .error(&format!("Failed to save {}: {}", path.display(), e)),
+        }
+    }
+
+    /// 自动登录: 记着密码时直接 /login, 否则只发用户名
+    async fn prompt(
+        &mut self,
+        connection: &mut Connection,
+        link: &mut Link,
+        text: String,
+    ) -> Result<()> {
+        let password_for = text
+            .strip_prefix("Password for ")
+            .and_then(|text| text.strip_suffix(':'));
+        if link.auto_login {
+            link.auto_login = false;
+            let line = match (&self.session.account, password_for, &self.session.username) {
+                (Some((_, password)), Some(_), _) => Some(password.clone()),
+                (Some((account, password)), None, _) => {
+                    Some(format!("/login {} {}", account, password))
+                }
+                (None, None, Some(username)) => Some(username.clone()),
+                _ => None,
+            };
+            if let Some(line) = line {
+                return send(connection, link, line, Sent::Auto).await;
+            }
+        }
+        link.awaiting_password = password_for.is_some();
+        self.output.print(text, None);
+        Ok(())
+    }
+
+    /// 重连之后回到原来的名字和房间
+    async fn restore(&mut self, connection: &mut Connection, link: &mut Link) -> Result<()> {
+        let mut lines = vec![];
+        match (&self.session.username, &self.session.account) {
+            (Some(username), Some((account, _))) if !username.eq_ignore_ascii_case(account) => {
+                lines.push(format!("/nick {}", username))
+            }
+            _ => {}
+        }
+        let rooms = &self.session.rooms;
+        let current = self.session.room.as_deref().unwrap_or(DEFAULT_ROOM);
+        for room in rooms
+            .iter()
+            .filter(|room| *room != DEFAULT_ROOM && *room != current)
+        {
+            lines.push(format!("/join {}", room));
+        }
+        if !rooms.is_empty() && !rooms.iter().any(|room| room == DEFAULT_ROOM) {
+            lines.push(format!("/leave {}", DEFAULT_ROOM));
+        }
+        // 最后 join 的房间就是当前房间
+        if lines.iter().any(|line| line.starts_with("/join")) || current != DEFAULT_ROOM {
+            lines.push(format!("/join {}", current));
+        }
+        for line in lines {
+            send(connection, link, line, Sent::Auto).await?;
+        }
+        Ok(())
+    }
+
+    /// 服务端确认成功之后更新本地记着的名字和账号
+    fn applied(&mut self, link: &Link, sent: Sent) {
+        let line = match sent {
+            Sent::Line(line) => line,
+            Sent::Password(password) => {
+                if let Some(username) = &self.session.username {
+                    self.session.account = Some((username.clone(), password));
+                }
+                return;
+            }
+            Sent::Auto | Sent::Upload(_) => return,
+        };
+        let line = line.trim();
+        let Some(command) = line.strip_prefix('/') else {
+            // 登录前的一行就是用户名
+            if !link.logged_in {
+                self.session.username = Some(line.to_string());
+            }
+            return;
+        };
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        let mut args = args.split_whitespace();
+        match (name, args.next(), args.next()) {
+            ("nick", Some(username), None) => self.session.username = Some(username.to_string()),
+            ("login", Some(account), Some(password)) => {
+                self.session.username = Some(account.to_string());
+                self.session.account = Some((account.to_string(), password.to_string()));
+            }
+            ("register", Some(password), None) => {
+                if let Some(username) = &self.session.username {
+                    self.session.account = Some((username.clone(), password.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// 客户端自己处理的命令, 返回 false 时发给服务端
+    fn local_command(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        let Some(args) = line.strip_prefix("/scrollback") else {
+            return false;
+        };
+        match args.trim() {
+            "" => self.output.replay(50),
+            n => match n.parse() {
+                Ok(n) => self.output.replay(n),
+                Err(_) => self.output.error("Usage: /scrollback [n]"),
+            },
+        }
+        true
+    }
+
+    fn show(&mut self, message: &Message) {
+        // 重连后回放的历史里已经显示过的跳过
+        if message.replayed && message.id != 0 && message.id <= self.session.last_id {
+            return;
+        }
+        self.session.last_id = self.session.last_id.max(message.id);
+        let note = self.session.apply(message);
+        let mention = matches!(
+            message.kind,
+            MessageKind::Chat | MessageKind::Action | MessageKind::Private
+        ) && !self.is_me(&message.sender)
+            && self
+                .session
+                .username
+                .as_deref()
+                .is_some_and(|username| mentions(&message.content, username));
+        let style = match message.kind {
+            _ if mention => Some(MENTION),
+            MessageKind::Private => Some(MAGENTA),
+            MessageKind::System
+            | MessageKind::Join
+            | MessageKind::Leave
+            | MessageKind::Edit
+            | MessageKind::Delete
+            | MessageKind::React
+            | MessageKind::Unreact => Some(DIM),
+            MessageKind::Chat | MessageKind::Action | MessageKind::File => None,
+        };
+        let mut line = match message.replayed {
+            true => message.to_string(),
+            false => format!(
+                "{} {}",
+                message.timestamp.with_timezone(&Local).format("%H:%M"),
+                message
+            ),
+        };
+        if let Some(note) = note {
+            line = format!("{} ({})", line, note);
+        }
+        self.output.print(line, style);
+        if mention && !message.replayed {
+            self.output.bell();
+        }
+    }
+
+    fn is_me(&self, username: &str) -> bool {
+        self.session
+            .username
+            .as_deref()
+            .is_some_and(|me| me.eq_ignore_ascii_case(username))
+    }
+}
+
+impl Session {
+    /// 编辑, 删除和 reaction 应用到本地记着的消息上, 返回显示在事件后面的说明
+    fn apply(&mut self, message: &Message) -> Option<String> {
+        if message.is_targetable() {
+            let entry = Entry {
+                message: message.clone(),
+                reactions: BTreeMap::new(),
+            };
+            self.messages.insert(message.id, entry);
+            while self.messages.len() > SCROLLBACK {
+                self.messages.pop_first();
+            }
+            return None;
+        }
+        let target = message.target?;
+        if message.kind == MessageKind::Delete {
+            let entry = self.messages.remove(&target)?;
+            return Some(format!("was: {}", entry.message.content));
+        }
+        let entry = self.messages.get_mut(&target)?;
+        match message.kind {
+            MessageKind::Edit => {
+                let old = std::mem::replace(&mut entry.message.content, message.content.clone());
+                return Some(format!("was: {}", old));
+            }
+            MessageKind::React => {
+                let users = entry.reactions.entry(message.content.clone()).or_default();
+                users.insert(message.sender.clone());
+            }
+            MessageKind::Unreact => {
+                if let Some(users) = entry.reactions.get_mut(&message.content) {
+                    users.remove(&message.sender);
+                    if users.is_empty() {
+                        entry.reactions.remove(&message.content);
+                    }
+                }
+            }
+            _ => return None,
+        }
+        let reactions: Vec<String> = entry
+            .reactions
+            .iter()
+            .map(|(emoji, users)| format!("{} {}", emoji, users.len()))
+            .collect();
+        match reactions.is_empty() {
+            true => Some("no reactions".to_string()),
+            false => Some(reactions.join(", ")),
+        }
+    }
+}
+
+impl Output {
+    fn print(&mut self, line: String, style: Option<&str>) {
+        self.scrollback.push_back(line.clone());
+        if self.scrollback.len() > SCROLLBACK {
+            self.scrollback.pop_front();
+        }
+        match (self.color, style) {
+            (true, Some(style)) => self.emit(format!("{}{}{}", style, line, RESET)),
+            _ => self.emit(line),
+        }
+    }
+
+    fn system(&mut self, text: &str) {
+        self.print(format!("-- {}", text), Some(DIM));
+    }
+
+    fn error(&mut self, text: &str) {
+        self.print(format!("!! {}", text), Some(RED));
+    }
+
+    fn bell(&mut self) {
+        if self.color {
+            self.emit("\x07".to_string());
+        }
+    }
+
+    /// 重新显示最近 n 行
+    fn replay(&mut self, n: usize) {
+        let skip = self.scrollback.len().saturating_sub(n);
+        let lines: Vec<String> = self.scrollback.iter().skip(skip).cloned().collect();
+        self.emit(format!("-- Last {} lines --", lines.len()));
+        for line in lines {
+            self.emit(line);
+        }
+    }
+
+    fn emit(&mut self, line: String) {
+        let line = match &mut self.printer {
+            Some(printer) => match printer.print(line) {
+                Ok(()) => return,
+                Err(e) => format!("failed to print: {}", e),
+            },
+            None => line,
+        };
+        println!("{}", line);
+    }
+}
+
+async fn send(
+    connection: &mut Connection,
+    link: &mut Link,
+    line: String,
+    sent: Sent,
+) -> Result<()> {
+    link.next_id += 1;
+    link.pending.insert(link.next_id, (sent, false));
+    connection
+        .send(ClientFrame::Send {
+            id: link.next_id,
+            line,
+        })
+        .await?;
+    Ok(())
+}
+
+/// 文件内容分成多个 Chunk 帧, 每帧服务端都会回 Ack
+async fn send_chunks(connection: &mut Connection, link: &mut Link, data: Bytes) -> Result<()> {
+    for start in (0..data.len()).step_by(UPLOAD_CHUNK) {
+        let chunk = data.slice(start..data.len().min(start + UPLOAD_CHUNK));
+        link.next_id += 1;
+        link.pending.insert(link.next_id, (Sent::Auto, false));
+        connection
+            .feed(ClientFrame::chunk(link.next_id, chunk))
+            .await?;
+    }
+    connection.flush().await?;
+    Ok(())
+}
+
+/// 按单词匹配自己的名字, 不区分大小写
+fn mentions(content: &str, username: &str) -> bool {
+    content
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+        .any(|word| word.eq_ignore_ascii_case(username))
+}
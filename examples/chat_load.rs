@@ -0,0 +1,471 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ecosystem::chat::{ClientFrame, FrameCodec, MessageKind, ServerFrame, PROTOCOL_VERSION};
+use futures::{SinkExt, StreamExt};
+use nanoid::nanoid;
+use serde::Serialize;
+use serde_with::{serde_as, DurationSeconds};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval_at, sleep, sleep_until, timeout, MissedTickBehavior};
+use tokio_util::codec::Framed;
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+// 压测消息的前缀, 后面是 这次压测的 id 发送者 序号 发送时间(微秒)
+const TAG: &str = "load";
+// 登录后所有人都在这个房间
+const ROOM: &str = "lobby";
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+// 每隔多久看一次服务端的内存
+const MEMORY_INTERVAL: Duration = Duration::from_millis(500);
+
+type Connection = Framed<TcpStream, FrameCodec<ServerFrame, ClientFrame>>;
+
+/// 服务端要把限速调高, 比如 CHAT_RATE_LIMIT=1000/1000, 否则发得快的消息会被拒绝
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+struct Options {
+    // 服务端的结构化协议端口
+    addr: String,
+    clients: usize,
+    // 每个客户端每秒发多少条
+    rate: f64,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    duration: Duration,
+    // 停止发送后再等多久, 还没收到的算丢了
+    #[serde_as(as = "DurationSeconds<u64>")]
+    grace: Duration,
+    // 同时在握手的连接数
+    concurrency: usize,
+    // 服务端的进程, 用来看内存, 没配时按进程名 chat 找
+    server_pid: Option<u32>,
+    #[serde(skip)]
+    output: Option<PathBuf>,
+    // 每次压测不一样, 房间的历史和 transcript 里以前压测的消息不算
+    run: String,
+}
+
+/// 一个客户端的统计
+#[derive(Debug, Default)]
+struct Stats {
+    sent: u64,
+    // 服务端 Ack 了并且没有回 Error 的, 只有这些会发到房间里
+    acked: u64,
+    rejected: u64,
+    received: u64,
+    // 收到的压测消息的延迟, 微秒
+    latencies: Vec<u64>,
+    // 服务端提醒我们落后时说丢了多少条
+    server_dropped: u64,
+    errors: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    started_at: DateTime<Utc>,
+    options: Options,
+    // 所有客户端握手完用了多久
+    connect_secs: f64,
+    connected: usize,
+    failed: usize,
+    sent: u64,
+    // 被服务端拒绝的, 比如被限速
+    rejected: u64,
+    // 每条成功的消息应该发给房间里所有人, 包括自己
+    expected: u64,
+    received: u64,
+    dropped: u64,
+    server_dropped: u64,
+    errors: u64,
+    // 每秒实际送达的条数
+    deliveries_per_sec: f64,
+    latency_ms: Option<Latency>,
+    server_memory: Option<Memory>,
+}
+
+#[derive(Debug, Serialize)]
+struct Latency {
+    mean: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    p999: f64,
+    max: f64,
+}
+
+/// 服务端的 RSS, KB
+#[derive(Debug, Serialize)]
+struct Memory {
+    pid: u32,
+    start_kb: u64,
+    peak_kb: u64,
+    end_kb: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = fmt::Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+
+    let options = Arc::new(Options::from_env()?);
+    info!(
+        "Starting run {}: {} clients against {}, {} msg/s each for {:?}",
+        options.run, options.clients, options.addr, options.rate, options.duration
+    );
+    let started_at = Utc::now();
+    let epoch = Instant::now();
+    let memory = options.server_pid.or_else(find_server).map(|pid| {
+        let (stop, stopped) = watch::channel(false);
+        (stop, tokio::spawn(sample_memory(pid, stopped)))
+    });
+
+    // 先让所有人登录完, 再一起开始发
+    let (start, started) = watch::channel(None);
+    let (ready, mut logins) = mpsc::channel(options.clients.max(1));
+    let permits = Arc::new(tokio::sync::Semaphore::new(options.concurrency));
+    let mut clients = Vec::with_capacity(options.clients);
+    for index in 0..options.clients {
+        let options = options.clone();
+        let started = started.clone();
+        let ready = ready.clone();
+        let permits = permits.clone();
+        clients.push(tokio::spawn(async move {
+            let connection = {
+                let _permit = permits.acquire().await?;
+                login(&options, index).await
+            };
+            // 报告完就放掉 sender, 所有人都报告了 logins 才会结束
+            let _ = ready.send(connection.is_ok()).await;
+            drop(ready);
+            let connection = connection?;
+            run_client(connection, &options, index, epoch, started).await
+        }));
+    }
+    drop(ready);
+    let mut connected = 0;
+    while let Some(ok) = logins.recv().await {
+        connected += ok as usize;
+    }
+    let connect_secs = epoch.elapsed().as_secs_f64();
+    let failed = options.clients - connected;
+    info!(
+        "{} clients logged in after {:.2}s, {} failed",
+        connected, connect_secs, failed
+    );
+    let start_at = Instant::now();
+    start.send_replace(Some(start_at));
+
+    let mut total = Stats::default();
+    for client in clients {
+        match client.await? {
+            Ok(stats) => total.merge(stats),
+            Err(e) => warn!("Client failed: {:#}", e),
+        }
+    }
+    let server_memory = match memory {
+        Some((stop, sampler)) => {
+            let _ = stop.send(true);
+            sampler.await?
+        }
+        None => None,
+    };
+
+    let delivered = total.acked.saturating_sub(total.rejected);
+    let expected = delivered * connected as u64;
+    let report = Report {
+        started_at,
+        options: options.as_ref().clone(),
+        connect_secs,
+        connected,
+        failed,
+        sent: total.sent,
+        rejected: total.rejected,
+        expected,
+        received: total.received,
+        dropped: expected.saturating_sub(total.received),
+        server_dropped: total.server_dropped,
+        errors: total.errors,
+        deliveries_per_sec: total.received as f64 / options.duration.as_secs_f64(),
+        latency_ms: Latency::from_micros(total.latencies),
+        server_memory,
+    };
+    let json = serde_json::to_string_pretty(&report)?;
+    match &options.output {
+        Some(path) => {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(path, &json).await?;
+            info!("Results written to {}", path.display());
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+impl Options {
+    /// CHAT_LOAD_ADDR, CHAT_LOAD_CLIENTS, CHAT_LOAD_RATE, CHAT_LOAD_DURATION, CHAT_LOAD_GRACE,
+    /// CHAT_LOAD_CONCURRENCY, CHAT_LOAD_SERVER_PID, CHAT_LOAD_OUTPUT
+    fn from_env() -> Result<Self> {
+        let var = |name: &str| env::var(format!("CHAT_LOAD_{}", name)).ok();
+        let mut options = Self {
+            addr: "127.0.0.1:8082".to_string(),
+            clients: 100,
+            rate: 1.0,
+            duration: Duration::from_secs(10),
+            grace: Duration::from_secs(3),
+            concurrency: 64,
+            server_pid: None,
+            output: None,
+            run: nanoid!(8),
+        };
+        if let Some(addr) = var("ADDR") {
+            options.addr = addr;
+        }
+        if let Some(clients) = var("CLIENTS") {
+            options.clients = clients.parse()?;
+        }
+        if let Some(rate) = var("RATE") {
+            options.rate = rate.parse()?;
+        }
+        if let Some(secs) = var("DURATION") {
+            options.duration = Duration::from_secs(secs.parse()?);
+        }
+        if let Some(secs) = var("GRACE") {
+            options.grace = Duration::from_secs(secs.parse()?);
+        }
+        if let Some(concurrency) = var("CONCURRENCY") {
+            options.concurrency = concurrency.parse()?;
+        }
+        if let Some(pid) = var("SERVER_PID") {
+            options.server_pid = Some(pid.parse()?);
+        }
+        options.output = var("OUTPUT").map(PathBuf::from);
+        if options.rate <= 0.0 || options.duration.is_zero() || options.concurrency == 0 {
+            return Err(anyhow!("rate, duration and concurrency must be positive"));
+        }
+        Ok(options)
+    }
+}
+
+/// 连接并完成握手和用户名, 收到自己加入房间就算登录完了
+async fn login(options: &Options, index: usize) -> Result<Connection> {
+    let username = format!("{}{}", TAG, index);
+    let stream = TcpStream::connect(&options.addr).await?;
+    stream.set_nodelay(true)?;
+    let mut connection = Framed::new(stream, FrameCodec::new());
+    connection
+        .send(ClientFrame::Hello {
+            version: PROTOCOL_VERSION,
+        })
+        .await?;
+    let handshake = async {
+        while let Some(frame) = connection.next().await {
+            match frame? {
+                ServerFrame::Prompt { .. } => {
+                    let line = username.clone();
+                    connection.send(ClientFrame::Send { id: 0, line }).await?;
+                }
+                ServerFrame::Join {
+                    room,
+                    username: name,
+                    ..
+                } if room == ROOM && name == username => return Ok(()),
+                ServerFrame::Error { reason, .. } => return Err(anyhow!(reason)),
+                ServerFrame::Ping { nonce } => connection.send(ClientFrame::Pong { nonce }).await?,
+                _ => {}
+            }
+        }
+        Err(anyhow!("Connection closed during login"))
+    };
+    timeout(LOGIN_TIMEOUT, handshake)
+        .await
+        .map_err(|_| anyhow!("Login timed out"))??;
+    Ok(connection)
+}
+
+async fn run_client(
+    mut connection: Connection,
+    options: &Options,
+    index: usize,
+    epoch: Instant,
+    mut started: watch::Receiver<Option<Instant>>,
+) -> Result<Stats> {
+    let mut stats = Stats::default();
+    let start = loop {
+        let start = *started.borrow_and_update();
+        if let Some(start) = start {
+            break start;
+        }
+        tokio::select! {
+            frame = connection.next() => match frame {
+                Some(frame) => stats.handle(&mut connection, frame?, &options.run, epoch).await?,
+                None => return Err(anyhow!("Connection closed before start")),
+            },
+            changed = started.changed() => changed?,
+        }
+    };
+    let period = Duration::from_secs_f64(1.0 / options.rate);
+    // 错开每个客户端第一次发的时间, 不要所有人同时发
+    let offset = period.mul_f64(index as f64 / options.clients as f64);
+    let mut ticker = interval_at((start + offset).into(), period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let stop_sending = tokio::time::Instant::from(start + options.duration);
+    let end = sleep_until((start + options.duration + options.grace).into());
+    tokio::pin!(end);
+    let mut sending = true;
+    let mut seq: u64 = 0;
+    loop {
+        tokio::select! {
+            frame = connection.next() => match frame {
+                Some(frame) => stats.handle(&mut connection, frame?, &options.run, epoch).await?,
+                None => break,
+            },
+            at = ticker.tick(), if sending => {
+                if at >= stop_sending {
+                    sending = false;
+                    continue;
+                }
+                seq += 1;
+                let sent_at = epoch.elapsed().as_micros();
+                let line = format!("{} {} {} {} {}", TAG, options.run, index, seq, sent_at);
+                connection.send(ClientFrame::Send { id: seq, line }).await?;
+                stats.sent += 1;
+            }
+            _ = &mut end => break,
+        }
+    }
+    let _ = connection
+        .send(ClientFrame::Send {
+            id: seq + 1,
+            line: "/quit".to_string(),
+        })
+        .await;
+    Ok(stats)
+}
+
+impl Stats {
+    async fn handle(
+        &mut self,
+        connection: &mut Connection,
+        frame: ServerFrame,
+        run: &str,
+        epoch: Instant,
+    ) -> Result<()> {
+        match frame {
+            // 加入房间时回放的历史不是这次发的
+            ServerFrame::Message(message)
+                if message.kind == MessageKind::Chat && !message.replayed =>
+            {
+                let mut fields = message.content.split(' ');
+                if fields.next() != Some(TAG) || fields.next() != Some(run) {
+                    return Ok(());
+                }
+                let sent_at: Option<u64> = fields.nth(2).and_then(|at| at.parse().ok());
+                if let Some(sent_at) = sent_at {
+                    let now = epoch.elapsed().as_micros() as u64;
+                    self.latencies.push(now.saturating_sub(sent_at));
+                    self.received += 1;
+                }
+            }
+            // 服务端的提醒: "Warning: you are lagging behind, N messages were dropped"
+            ServerFrame::Message(message) if message.kind == MessageKind::System => {
+                let dropped = message
+                    .content
+                    .strip_prefix("Warning: you are lagging behind, ")
+                    .and_then(|rest| rest.split(' ').next())
+                    .and_then(|n| n.parse::<u64>().ok());
+                self.server_dropped += dropped.unwrap_or_default();
+            }
+            // 登录时用户名那一帧的 id 是 0, 不算
+            ServerFrame::Error { id, .. } => {
+                self.errors += 1;
+                self.rejected += id.is_some_and(|id| id > 0) as u64;
+            }
+            ServerFrame::Ack { id } if id > 0 => self.acked += 1,
+            ServerFrame::Ping { nonce } => connection.send(ClientFrame::Pong { nonce }).await?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Stats) {
+        self.sent += other.sent;
+        self.acked += other.acked;
+        self.rejected += other.rejected;
+        self.received += other.received;
+        self.latencies.extend(other.latencies);
+        self.server_dropped += other.server_dropped;
+        self.errors += other.errors;
+    }
+}
+
+impl Latency {
+    fn from_micros(mut latencies: Vec<u64>) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_unstable();
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        let percentile = |p: f64| {
+            let index = ((latencies.len() as f64 * p).ceil() as usize).saturating_sub(1);
+            ms(latencies[index.min(latencies.len() - 1)])
+        };
+        let sum: u64 = latencies.iter().sum();
+        Some(Self {
+            mean: ms(sum / latencies.len() as u64),
+            p50: percentile(0.50),
+            p90: percentile(0.90),
+            p99: percentile(0.99),
+            p999: percentile(0.999),
+            max: ms(latencies[latencies.len() - 1]),
+        })
+    }
+}
+
+/// 按进程名找服务端, 只在 Linux 上有用
+fn find_server() -> Option<u32> {
+    let entries = std::fs::read_dir("/proc").ok()?;
+    entries.flatten().find_map(|entry| {
+        let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+        let comm = std::fs::read_to_string(entry.path().join("comm")).ok()?;
+        (comm.trim() == "chat").then_some(pid)
+    })
+}
+
+fn rss_kb(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+async fn sample_memory(pid: u32, mut stopped: watch::Receiver<bool>) -> Option<Memory> {
+    let start_kb = rss_kb(pid)?;
+    let mut memory = Memory {
+        pid,
+        start_kb,
+        peak_kb: start_kb,
+        end_kb: start_kb,
+    };
+    loop {
+        tokio::select! {
+            _ = sleep(MEMORY_INTERVAL) => {}
+            _ = stopped.wait_for(|stopped| *stopped) => break,
+        }
+        let Some(rss) = rss_kb(pid) else { break };
+        memory.peak_kb = memory.peak_kb.max(rss);
+        memory.end_kb = rss;
+    }
+    Some(memory)
+}
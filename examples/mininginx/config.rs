@@ -0,0 +1,437 @@
+use crate::health::HealthConfig;
+use crate::limiter::LimitConfig;
+use crate::upstream::{Strategy, UpstreamConfig};
+use anyhow::{anyhow, Context, Result};
+use ecosystem::tls::TlsConfig;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr, DurationSeconds};
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+// 只用环境变量配置时 upstream 组的名字
+const DEFAULT_GROUP: &str = "default";
+
+/// 配置文件是 TOML, 例如
+///
+/// ```toml
+/// drain_timeout = 30
+///
+/// [limits]
+/// max_connections = 10000
+/// max_connections_per_ip = 100
+/// rate = 20
+/// burst = 50
+///
+/// [upstreams.shortener]
+/// servers = ["127.0.0.1:9876", "127.0.0.1:9877=2"]
+/// strategy = "weighted"
+/// health = { interval = 5, path = "/health" }
+///
+/// [upstreams.users]
+/// servers = ["127.0.0.1:8081"]
+///
+/// [[listeners]]
+/// listen = "0.0.0.0:8001"
+/// upstream = "shortener"
+/// connect_timeout = 3
+///
+/// # http 模式按 host 和 path 前缀选组, 都不匹配时用 upstream
+/// [[listeners]]
+/// listen = "0.0.0.0:8000"
+/// mode = "http"
+/// upstream = "shortener"
+/// routes = [{ path = "/users", upstream = "users", strip_prefix = true }]
+/// ```
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    // 组名 -> upstream 组, listener 按名字引用
+    pub upstreams: BTreeMap<String, GroupConfig>,
+    // 退出时等已有的连接结束, 超过这个时间直接断开
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: Duration,
+    #[serde(default)]
+    pub limits: LimitConfig,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub listen: SocketAddr,
+    pub upstream: String,
+    // 连 upstream 最多等多久, 超时算一次失败
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: Duration,
+    // 客户端到 proxy 这一段走 TLS, 到 upstream 还是明文
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub mode: Mode,
+    // 只有 http 模式能用, 越具体的越优先
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+/// tcp 只转发字节, http 解析 HTTP/1.1 请求再转发
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Tcp,
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    // 不带端口, 不设置时匹配所有 host
+    pub host: Option<String>,
+    // 按 / 分段匹配前缀, /api 匹配 /api 和 /api/x, 不匹配 /apix
+    #[serde(default = "default_path")]
+    pub path: String,
+    pub upstream: String,
+    // 转发前去掉匹配的前缀, /users/1 变成 /1
+    #[serde(default)]
+    pub strip_prefix: bool,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    // host:port 或者 host:port=权重
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub servers: Vec<UpstreamConfig>,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default)]
+    pub health: HealthConfig,
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_connect_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+impl Config {
+    /// 读取并检查配置文件, 有错时返回 Err, 调用方继续用原来的配置
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let config: Self = toml::from_str(&content)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 没有配置文件时只有一个 listener, MININGINX_LISTEN_ADDR, MININGINX_MODE,
+    /// MININGINX_UPSTREAMS (逗号分隔, 每个是 host:port 或 host:port=权重), MININGINX_STRATEGY,
+    /// MININGINX_HEALTH_INTERVAL (秒, off 表示不做主动检查) / MININGINX_HEALTH_TIMEOUT /
+    /// MININGINX_HEALTH_PATH / MININGINX_HEALTH_RISE / MININGINX_HEALTH_FALL,
+    /// MININGINX_MAX_FAILS / MININGINX_FAIL_TIMEOUT, MININGINX_CONNECT_TIMEOUT,
+    /// MININGINX_TLS_CERT / MININGINX_TLS_KEY / MININGINX_TLS_CLIENT_CA, MININGINX_DRAIN_TIMEOUT,
+    /// MININGINX_MAX_CONNECTIONS / MININGINX_MAX_CONNECTIONS_PER_IP, MININGINX_RATE_LIMIT (每秒/最多攒多少)
+    pub fn from_env() -> Result<Self> {
+        let mut listener = ListenerConfig {
+            listen: "0.0.0.0:8001".parse()?,
+            upstream: DEFAULT_GROUP.to_string(),
+            connect_timeout: default_connect_timeout(),
+            tls: TlsConfig::from_env("MININGINX_TLS")?,
+            mode: Mode::Tcp,
+            routes: vec![],
+        };
+        let mut group = GroupConfig {
+            servers: vec![UpstreamConfig {
+                addr: "0.0.0.0:8080".to_string(),
+                weight: 1,
+            }],
+            strategy: Strategy::default(),
+            health: HealthConfig::default(),
+        };
+        let mut drain_timeout = default_drain_timeout();
+        let mut limits = LimitConfig::default();
+        if let Ok(addr) = std::env::var("MININGINX_LISTEN_ADDR") {
+            listener.listen = addr.parse()?;
+        }
+        if let Ok(mode) = std::env::var("MININGINX_MODE") {
+            listener.mode = match mode.as_str() {
+                "tcp" => Mode::Tcp,
+                "http" => Mode::Http,
+                _ => return Err(anyhow!("invalid mode {}, expect tcp or http", mode)),
+            };
+        }
+        if let Ok(upstreams) = std::env::var("MININGINX_UPSTREAMS") {
+            group.servers = upstreams
+                .split(',')
+                .map(str::trim)
+                .filter(|upstream| !upstream.is_empty())
+                .map(str::parse)
+                .collect::<Result<_>>()?;
+        }
+        if let Ok(strategy) = std::env::var("MININGINX_STRATEGY") {
+            group.strategy = strategy.parse()?;
+        }
+        if let Ok(secs) = std::env::var("MININGINX_HEALTH_INTERVAL") {
+            group.health.interval = match secs.as_str() {
+                "" | "off" | "0" => None,
+                secs => Some(Duration::from_secs(secs.parse()?)),
+            };
+        }
+        if let Ok(secs) = std::env::var("MININGINX_HEALTH_TIMEOUT") {
+            group.health.timeout = Duration::from_secs(secs.parse()?);
+        }
+        if let Ok(path) = std::env::var("MININGINX_HEALTH_PATH") {
+            group.health.http_path = Some(path);
+        }
+        if let Ok(rise) = std::env::var("MININGINX_HEALTH_RISE") {
+            group.health.rise = rise.parse()?;
+        }
+        if let Ok(fall) = std::env::var("MININGINX_HEALTH_FALL") {
+            group.health.fall = fall.parse()?;
+        }
+        if let Ok(fails) = std::env::var("MININGINX_MAX_FAILS") {
+            group.health.max_fails = fails.parse()?;
+        }
+        if let Ok(secs) = std::env::var("MININGINX_FAIL_TIMEOUT") {
+            group.health.fail_timeout = Duration::from_secs(secs.parse()?);
+        }
+        if let Ok(secs) = std::env::var("MININGINX_CONNECT_TIMEOUT") {
+            listener.connect_timeout = Duration::from_secs(secs.parse()?);
+        }
+        if let Ok(secs) = std::env::var("MININGINX_DRAIN_TIMEOUT") {
+            drain_timeout = Duration::from_secs(secs.parse()?);
+        }
+        if let Ok(max) = std::env::var("MININGINX_MAX_CONNECTIONS") {
+            limits.max_connections = max.parse()?;
+        }
+        if let Ok(max) = std::env::var("MININGINX_MAX_CONNECTIONS_PER_IP") {
+            limits.max_connections_per_ip = max.parse()?;
+        }
+        if let Ok(rate) = std::env::var("MININGINX_RATE_LIMIT") {
+            let (rate, burst) = rate
+                .split_once('/')
+                .ok_or_else(|| anyhow!("invalid rate limit {}, expect <rate>/<burst>", rate))?;
+            limits.rate = rate.parse()?;
+            limits.burst = burst.parse()?;
+        }
+        let config = Self {
+            listeners: vec![listener],
+            upstreams: BTreeMap::from([(DEFAULT_GROUP.to_string(), group)]),
+            drain_timeout,
+            limits,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            return Err(anyhow!("at least one listener is required"));
+        }
+        let limits = &self.limits;
+        if !limits.rate.is_finite() || limits.rate < 0.0 {
+            return Err(anyhow!("rate limit must not be negative"));
+        }
+        if limits.rate > 0.0 && limits.burst == 0 {
+            return Err(anyhow!("rate limit burst must be positive"));
+        }
+        let mut addrs = HashSet::new();
+        for listener in &self.listeners {
+            if !addrs.insert(listener.listen) {
+                return Err(anyhow!("duplicate listener {}", listener.listen));
+            }
+            if !self.upstreams.contains_key(&listener.upstream) {
+                return Err(anyhow!(
+                    "listener {} uses unknown upstream group {}",
+                    listener.listen,
+                    listener.upstream
+                ));
+            }
+            if listener.mode == Mode::Tcp && !listener.routes.is_empty() {
+                return Err(anyhow!(
+                    "listener {}: routes need mode = \"http\"",
+                    listener.listen
+                ));
+            }
+            for route in &listener.routes {
+                if !route.path.starts_with('/') {
+                    return Err(anyhow!(
+                        "listener {}: route path {} must start with /",
+                        listener.listen,
+                        route.path
+                    ));
+                }
+                if !self.upstreams.contains_key(&route.upstream) {
+                    return Err(anyhow!(
+                        "listener {}: route {} uses unknown upstream group {}",
+                        listener.listen,
+                        route.path,
+                        route.upstream
+                    ));
+                }
+            }
+        }
+        for (name, group) in &self.upstreams {
+            if group.servers.is_empty() {
+                return Err(anyhow!("upstream group {} has no servers", name));
+            }
+            let health = &group.health;
+            if health.rise == 0 || health.fall == 0 {
+                return Err(anyhow!(
+                    "upstream group {}: health check rise and fall must be positive",
+                    name
+                ));
+            }
+            if health
+                .http_path
+                .as_ref()
+                .is_some_and(|p| !p.starts_with('/'))
+            {
+                return Err(anyhow!(
+                    "upstream group {}: health check path must start with /",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPSTREAMS: &str = r#"
+[upstreams.web]
+servers = ["127.0.0.1:8080", "127.0.0.1:8081=2"]
+strategy = "weighted"
+
+[upstreams.api]
+servers = ["127.0.0.1:9090"]
+health = { interval = 0, path = "/health" }
+"#;
+
+    fn check(config: &str) -> Result<Config> {
+        // 顶层的字段要放在所有的表前面
+        let config: Config = toml::from_str(&format!("{}\n{}", config, UPSTREAMS))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn error(config: &str) -> String {
+        format!("{:#}", check(config).unwrap_err())
+    }
+
+    #[test]
+    fn valid_config_should_pass() {
+        let config = check(
+            r#"
+drain_timeout = 10
+limits = { max_connections = 100, rate = 5, burst = 10 }
+
+[[listeners]]
+listen = "127.0.0.1:8001"
+upstream = "web"
+
+[[listeners]]
+listen = "127.0.0.1:8000"
+mode = "http"
+upstream = "web"
+routes = [{ path = "/api", upstream = "api", strip_prefix = true }]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[1].routes[0].path, "/api");
+        assert_eq!(config.upstreams["web"].servers[1].weight, 2);
+        assert_eq!(config.upstreams["api"].health.interval, None);
+        assert_eq!(config.limits.max_connections, 100);
+        assert_eq!(config.drain_timeout, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn listeners_should_be_valid() {
+        assert!(error("listeners = []").contains("at least one listener"));
+        let duplicate = r#"
+[[listeners]]
+listen = "127.0.0.1:8001"
+upstream = "web"
+[[listeners]]
+listen = "127.0.0.1:8001"
+upstream = "api"
+"#;
+        assert!(error(duplicate).contains("duplicate listener"));
+        let unknown = r#"
+[[listeners]]
+listen = "127.0.0.1:8001"
+upstream = "nope"
+"#;
+        assert!(error(unknown).contains("unknown upstream group nope"));
+    }
+
+    #[test]
+    fn routes_should_be_valid() {
+        let listener = |extra: &str| {
+            format!(
+                "[[listeners]]\nlisten = \"127.0.0.1:8000\"\nupstream = \"web\"\n{}",
+                extra
+            )
+        };
+        let tcp = listener(r#"routes = [{ path = "/api", upstream = "api" }]"#);
+        assert!(error(&tcp).contains("routes need mode"));
+        let path = listener("mode = \"http\"\nroutes = [{ path = \"api\", upstream = \"api\" }]");
+        assert!(error(&path).contains("must start with /"));
+        let unknown =
+            listener("mode = \"http\"\nroutes = [{ path = \"/api\", upstream = \"nope\" }]");
+        assert!(error(&unknown).contains("unknown upstream group nope"));
+    }
+
+    #[test]
+    fn groups_should_be_valid() {
+        let listener = "[[listeners]]\nlisten = \"127.0.0.1:8000\"\nupstream = \"web\"\n";
+        let group = |group: &str| format!("{}\n[upstreams.extra]\n{}", listener, group);
+        assert!(error(&group("servers = []")).contains("has no servers"));
+        let rise = group("servers = [\"a:1\"]\nhealth = { rise = 0 }");
+        assert!(error(&rise).contains("rise and fall must be positive"));
+        let path = group("servers = [\"a:1\"]\nhealth = { path = \"health\" }");
+        assert!(error(&path).contains("path must start with /"));
+        // 解析的时候就会出错的
+        assert!(error(&group("servers = [\"a:1=0\"]")).contains("invalid upstream"));
+        assert!(error(&group("servers = [\"a:1=1000\"]")).contains("invalid upstream"));
+        let strategy = group("servers = [\"a:1\"]\nstrategy = \"random\"");
+        assert!(error(&strategy).contains("invalid strategy"));
+    }
+
+    #[test]
+    fn limits_should_be_valid() {
+        let listener = "[[listeners]]\nlisten = \"127.0.0.1:8000\"\nupstream = \"web\"\n";
+        let limits = |limits: &str| format!("limits = {}\n{}", limits, listener);
+        assert!(error(&limits("{ rate = -1.0 }")).contains("must not be negative"));
+        assert!(error(&limits("{ rate = nan }")).contains("must not be negative"));
+        assert!(error(&limits("{ rate = 1.0, burst = 0 }")).contains("burst must be positive"));
+        // 不限速时 burst 是多少都行
+        assert!(check(&limits("{ rate = 0.0, burst = 0 }")).is_ok());
+    }
+
+    #[test]
+    fn unknown_fields_should_be_rejected() {
+        let typo =
+            "[[listeners]]\nlisten = \"127.0.0.1:8000\"\nupstream = \"web\"\nconect_timeout = 1\n";
+        assert!(error(typo).contains("unknown field"));
+    }
+}
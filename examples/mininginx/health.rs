@@ -0,0 +1,212 @@
+use crate::upstream::{Pool, Upstream};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+use serde_with::{serde_as, DurationSeconds};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, MissedTickBehavior};
+use tracing::{info, warn};
+
+// HTTP 检查只看状态行, 最多读这么多字节
+const MAX_STATUS_LINE: usize = 1024;
+
+/// 配置文件里时间都是秒, interval = 0 表示不做主动检查
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    // 主动检查的间隔, None 表示不做主动检查
+    #[serde(deserialize_with = "interval")]
+    pub interval: Option<Duration>,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub timeout: Duration,
+    // 设置了就 GET 这个路径, 2xx/3xx 算正常; 否则只看 TCP 能不能连上
+    #[serde(rename = "path")]
+    pub http_path: Option<String>,
+    // 连续成功多少次算恢复, 连续失败多少次算挂了
+    pub rise: u32,
+    pub fall: u32,
+    // 被动检查: 转发时连续连不上这么多次就摘掉, 0 表示不摘
+    pub max_fails: u32,
+    // 摘掉多久以后再试
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub fail_timeout: Duration,
+}
+
+/// 一个 upstream 的健康状态, 主动检查和被动检查分开记
+#[derive(Debug)]
+pub struct Health {
+    healthy: bool,
+    // 主动检查连续成功/失败的次数
+    successes: u32,
+    failures: u32,
+    // 转发时连续连不上的次数
+    connect_failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(5)),
+            timeout: Duration::from_secs(2),
+            http_path: None,
+            rise: 2,
+            fall: 3,
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        // 启动时先当作正常的, 不用等第一轮检查
+        Self {
+            healthy: true,
+            successes: 0,
+            failures: 0,
+            connect_failures: 0,
+            ejected_until: None,
+        }
+    }
+}
+
+impl Health {
+    /// 能不能选它: 主动检查正常, 并且没有被摘掉 (或者摘掉的时间已经过了)
+    pub fn available(&mut self, addr: &str) -> bool {
+        if let Some(until) = self.ejected_until {
+            if Instant::now() < until {
+                return false;
+            }
+            info!(upstream = addr, "Upstream {} is back after ejection", addr);
+            self.ejected_until = None;
+            self.connect_failures = 0;
+        }
+        self.healthy
+    }
+
+    fn probed(&mut self, addr: &str, result: Result<()>, config: &HealthConfig) {
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                self.successes += 1;
+                if !self.healthy && self.successes >= config.rise {
+                    info!(
+                        upstream = addr,
+                        "Upstream {} is up after {} successful checks", addr, self.successes
+                    );
+                    self.healthy = true;
+                }
+            }
+            Err(e) => {
+                self.successes = 0;
+                self.failures += 1;
+                if self.healthy && self.failures >= config.fall {
+                    warn!(
+                        upstream = addr,
+                        "Upstream {} is down after {} failed checks: {:#}", addr, self.failures, e
+                    );
+                    self.healthy = false;
+                }
+            }
+        }
+    }
+
+    /// 转发时连 upstream 的结果
+    pub fn connected(&mut self, addr: &str, ok: bool, config: &HealthConfig) {
+        if ok {
+            self.connect_failures = 0;
+            return;
+        }
+        self.connect_failures += 1;
+        if config.max_fails > 0
+            && self.connect_failures >= config.max_fails
+            && self.ejected_until.is_none()
+        {
+            warn!(
+                upstream = addr,
+                "Ejecting upstream {} for {:?} after {} failed connections",
+                addr,
+                config.fail_timeout,
+                self.connect_failures
+            );
+            self.ejected_until = Some(Instant::now() + config.fail_timeout);
+        }
+    }
+}
+
+fn interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let secs = u64::deserialize(deserializer)?;
+    Ok((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+/// 每个 upstream 一个任务, 按 interval 检查, 没有配置 interval 时直接返回.
+/// 只拿 Weak, reload 换掉 pool 以后检查自己就停了
+pub async fn watch(pool: Weak<Pool>) {
+    let Some((interval, upstreams)) = pool
+        .upgrade()
+        .and_then(|p| Some((p.health().interval?, p.upstreams().to_vec())))
+    else {
+        return;
+    };
+    let checks: Vec<_> = upstreams
+        .into_iter()
+        .map(|upstream| tokio::spawn(check(pool.clone(), upstream, interval)))
+        .collect();
+    for check in checks {
+        let _ = check.await;
+    }
+}
+
+async fn check(pool: Weak<Pool>, upstream: Arc<Upstream>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        let config = pool.health();
+        let result = match timeout(config.timeout, probe(&upstream.addr, config)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("timed out after {:?}", config.timeout)),
+        };
+        upstream
+            .health
+            .lock()
+            .unwrap()
+            .probed(&upstream.addr, result, config);
+    }
+}
+
+async fn probe(addr: &str, config: &HealthConfig) -> Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let Some(path) = &config.http_path else {
+        return Ok(());
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: mininginx\r\nConnection: close\r\n\r\n",
+        path, addr
+    );
+    stream.write_all(request.as_bytes()).await?;
+    // 读到状态行结束就够了
+    let mut buf = Vec::new();
+    while !buf.windows(2).any(|w| w == b"\r\n") {
+        if buf.len() >= MAX_STATUS_LINE || stream.read_buf(&mut buf).await? == 0 {
+            return Err(anyhow!("incomplete status line"));
+        }
+    }
+    let line = String::from_utf8_lossy(&buf);
+    let status: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow!("invalid status line"))?;
+    if !(200..400).contains(&status) {
+        return Err(anyhow!("status {}", status));
+    }
+    Ok(())
+}
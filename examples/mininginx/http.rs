@@ -0,0 +1,441 @@
+use crate::config::RouteConfig;
+use crate::upstream::{Lease, Pool};
+use crate::{connect, Route};
+use axum::body::Body;
+use hyper::body::Incoming;
+use hyper::client::conn::http1::{self as client, SendRequest};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::uri::Authority;
+use hyper::server::conn::http1 as server;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Uri, Version};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{watch, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+// 加在 Via 里的名字
+const VIA: &str = "mininginx";
+// 只对这一跳有效, 不转发
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// http 模式下的一条路由, pool 是 reload 时按组名找到的
+#[derive(Debug, Clone)]
+pub struct HttpRoute {
+    host: Option<String>,
+    path: String,
+    strip_prefix: bool,
+    pool: Arc<Pool>,
+}
+
+/// 客户端连接上已经连好的 upstream, 后面的请求到同一个组时复用
+struct Upstream {
+    pool: Arc<Pool>,
+    // 连接关掉时才放掉, least-conn 看到的是连接数
+    lease: Lease,
+    sender: SendRequest<Incoming>,
+}
+
+impl HttpRoute {
+    pub fn new(config: &RouteConfig, pool: Arc<Pool>) -> Self {
+        Self {
+            host: config.host.as_ref().map(|host| host.to_ascii_lowercase()),
+            path: config.path.clone(),
+            strip_prefix: config.strip_prefix,
+            pool,
+        }
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match &self.host {
+            Some(expected) => host == Some(expected.as_str()),
+            None => true,
+        };
+        host_matches
+            && path.strip_prefix(&self.path).is_some_and(|rest| {
+                self.path.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+            })
+    }
+}
+
+/// 处理一个客户端连接上的所有请求 (keep-alive), stop 之后处理完当前的请求就关掉
+pub async fn serve_connection<S>(
+    client: S,
+    addr: SocketAddr,
+    routes: watch::Receiver<Route>,
+    stop: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let upstreams = Arc::new(Mutex::new(Vec::new()));
+    // 锁一直拿到 upstream 回了响应头, reuse_or_connect 里 ready() 还要等上一个响应的 body
+    // 发完, 所以同一个客户端连接上的请求是一个一个转发的, 前面的 body 慢后面的也得等.
+    // HTTP/1 本来就要按顺序回响应, 这样没问题
+    let service = service_fn(move |request| {
+        // 每个请求都用最新的配置, reload 以后 keep-alive 的连接也会换路由
+        let route = routes.borrow().clone();
+        let upstreams = upstreams.clone();
+        async move {
+            let mut upstreams = upstreams.lock().await;
+            Ok::<_, Infallible>(forward(request, addr, &route, &mut upstreams).await)
+        }
+    });
+    let connection = server::Builder::new().serve_connection(TokioIo::new(client), service);
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = stop.cancelled() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        warn!("HTTP connection from {} failed: {}", addr, e);
+    }
+}
+
+async fn forward(
+    mut request: Request<Incoming>,
+    client: SocketAddr,
+    route: &Route,
+    upstreams: &mut Vec<Upstream>,
+) -> Response<Body> {
+    let host = host(&request);
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let version = request.version();
+    let http = pick(route, host.as_deref(), &path);
+    let pool = http.map_or(&route.pool, |http| &http.pool);
+    prepare(&mut request, client, route.proto);
+    if let Some(http) = http.filter(|http| http.strip_prefix) {
+        // 去掉前缀后不是合法的路径时不能转发原来的路径
+        if !strip_prefix(&mut request, &http.path) {
+            warn!(
+                "Invalid path after stripping {} from {} {} from {}",
+                http.path, method, path, client
+            );
+            return status(StatusCode::BAD_REQUEST);
+        }
+    }
+    // reload 之后旧的组不会再用到, 连着它们的 upstream 要关掉, lease 也还回去
+    upstreams.retain(|upstream| uses(route, &upstream.pool));
+    let Some(upstream) = reuse_or_connect(upstreams, pool, client, route.connect_timeout).await
+    else {
+        warn!(
+            "No healthy upstream for {} {} from {}",
+            method, path, client
+        );
+        return status(StatusCode::SERVICE_UNAVAILABLE);
+    };
+    let addr = upstream.lease.addr.clone();
+    match upstream.sender.send_request(request).await {
+        Ok(response) => {
+            let mut response = response.map(Body::new);
+            strip_hop_by_hop(response.headers_mut());
+            append_via(response.headers_mut(), version);
+            info!(
+                "{} {} {} from {} via {}",
+                response.status().as_u16(),
+                method,
+                path,
+                client,
+                addr
+            );
+            response
+        }
+        Err(e) => {
+            warn!(
+                "Failed to forward {} {} from {} to {}: {}",
+                method, path, client, addr, e
+            );
+            // 这个连接不能再用了
+            upstreams.retain(|upstream| !Arc::ptr_eq(&upstream.pool, pool));
+            status(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// 匹配的路由里指定了 host 的优先, 然后是前缀长的, 一样时取先配置的; 都不匹配时用默认的组
+fn pick<'a>(route: &'a Route, host: Option<&str>, path: &str) -> Option<&'a HttpRoute> {
+    route
+        .http
+        .iter()
+        .rev()
+        .filter(|http| http.matches(host, path))
+        .max_by_key(|http| (http.host.is_some(), http.path.len()))
+}
+
+/// 这个 pool 是不是当前配置里的某个组
+fn uses(route: &Route, pool: &Arc<Pool>) -> bool {
+    Arc::ptr_eq(&route.pool, pool) || route.http.iter().any(|http| Arc::ptr_eq(&http.pool, pool))
+}
+
+async fn reuse_or_connect<'a>(
+    upstreams: &'a mut Vec<Upstream>,
+    pool: &Arc<Pool>,
+    client: SocketAddr,
+    connect_timeout: Duration,
+) -> Option<&'a mut Upstream> {
+    if let Some(i) = upstreams
+        .iter()
+        .position(|upstream| Arc::ptr_eq(&upstream.pool, pool))
+    {
+        // upstream 可能已经关掉了 keep-alive 的连接, 关了就重新连
+        if upstreams[i].sender.ready().await.is_ok() {
+            return Some(&mut upstreams[i]);
+        }
+        upstreams.swap_remove(i);
+    }
+    let (lease, stream) = connect(pool, client, connect_timeout).await?;
+    let (sender, connection) = match client::handshake(TokioIo::new(stream)).await {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            warn!("HTTP handshake with {} failed: {}", lease.addr, e);
+            return None;
+        }
+    };
+    // 在后台读写 upstream 的连接, sender drop 以后自己结束
+    tokio::spawn(connection);
+    upstreams.push(Upstream {
+        pool: pool.clone(),
+        lease,
+        sender,
+    });
+    upstreams.last_mut()
+}
+
+/// Host 头里的主机名, 小写, 不带端口
+fn host(request: &Request<Incoming>) -> Option<String> {
+    let authority = match request.headers().get(header::HOST) {
+        Some(value) => value.to_str().ok()?.parse::<Authority>().ok()?,
+        None => request.uri().authority()?.clone(),
+    };
+    Some(authority.host().to_ascii_lowercase())
+}
+
+fn prepare(request: &mut Request<Incoming>, client: SocketAddr, proto: &'static str) {
+    // 发给 upstream 的请求行只要路径, 没有 Host 时用绝对 URI 里的
+    if let Some(authority) = request.uri().authority().cloned() {
+        let path: Uri = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .parse()
+            .unwrap_or_default();
+        *request.uri_mut() = path;
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            request.headers_mut().entry(header::HOST).or_insert(host);
+        }
+    }
+    let version = request.version();
+    *request.version_mut() = Version::HTTP_11;
+    let headers = request.headers_mut();
+    strip_hop_by_hop(headers);
+    // 前面已经有代理时追加在后面
+    let ip = client.ip().to_string();
+    let forwarded = match headers
+        .get(&X_FORWARDED_FOR)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(previous) => format!("{}, {}", previous, ip),
+        None => ip,
+    };
+    if let Ok(forwarded) = HeaderValue::from_str(&forwarded) {
+        headers.insert(X_FORWARDED_FOR, forwarded);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    append_via(headers, version);
+}
+
+/// 返回 false 表示去掉前缀后不是合法的 URI
+fn strip_prefix<B>(request: &mut Request<B>, prefix: &str) -> bool {
+    let Some(path) = request.uri().path_and_query() else {
+        return true;
+    };
+    let rest = path.as_str()[prefix.trim_end_matches('/').len()..].to_string();
+    // 剩下的是空的或者只有 query 时补上 /
+    let path = match rest.starts_with('/') {
+        true => rest,
+        false => format!("/{}", rest),
+    };
+    match path.parse() {
+        Ok(uri) => {
+            *request.uri_mut() = uri;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Connection 里列出的头也只对这一跳有效
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+fn append_via(headers: &mut HeaderMap, version: Version) {
+    let version = match version {
+        Version::HTTP_10 => "1.0",
+        _ => "1.1",
+    };
+    if let Ok(via) = HeaderValue::from_str(&format!("{} {}", version, VIA)) {
+        headers.append(header::VIA, via);
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(status.canonical_reason().unwrap_or_default()));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Mode;
+    use crate::upstream::Strategy;
+
+    fn pool() -> Arc<Pool> {
+        Arc::new(Pool::new(
+            &["127.0.0.1:1".parse().unwrap()],
+            Strategy::RoundRobin,
+            Default::default(),
+        ))
+    }
+
+    fn http_route(host: Option<&str>, path: &str, strip_prefix: bool) -> HttpRoute {
+        let config = RouteConfig {
+            host: host.map(str::to_string),
+            path: path.to_string(),
+            upstream: "default".to_string(),
+            strip_prefix,
+        };
+        HttpRoute::new(&config, pool())
+    }
+
+    fn route(http: Vec<HttpRoute>) -> Route {
+        Route {
+            mode: Mode::Http,
+            pool: pool(),
+            connect_timeout: Duration::from_secs(1),
+            http,
+            tls: None,
+            proto: "http",
+        }
+    }
+
+    // 选中的路由的 (host, path)
+    fn picked<'a>(
+        route: &'a Route,
+        host: Option<&str>,
+        path: &str,
+    ) -> Option<(Option<&'a str>, &'a str)> {
+        pick(route, host, path).map(|http| (http.host.as_deref(), http.path.as_str()))
+    }
+
+    fn stripped(uri: &str, prefix: &str) -> String {
+        let mut request = Request::builder().uri(uri).body(()).unwrap();
+        assert!(strip_prefix(&mut request, prefix));
+        request.uri().to_string()
+    }
+
+    #[test]
+    fn prefix_should_match_whole_segments() {
+        let api = http_route(None, "/api", false);
+        assert!(api.matches(None, "/api"));
+        assert!(api.matches(None, "/api/"));
+        assert!(api.matches(None, "/api/users"));
+        assert!(!api.matches(None, "/apix"));
+        assert!(!api.matches(None, "/"));
+        // 以 / 结尾的前缀不匹配不带 / 的路径
+        let api = http_route(None, "/api/", false);
+        assert!(api.matches(None, "/api/users"));
+        assert!(!api.matches(None, "/api"));
+        let root = http_route(None, "/", false);
+        assert!(root.matches(None, "/"));
+        assert!(root.matches(None, "/anything"));
+    }
+
+    #[test]
+    fn host_should_match_case_insensitively() {
+        let http = http_route(Some("Example.COM"), "/", false);
+        assert!(http.matches(Some("example.com"), "/"));
+        assert!(!http.matches(Some("other.com"), "/"));
+        assert!(!http.matches(None, "/"));
+    }
+
+    #[test]
+    fn pick_should_prefer_host_then_longest_prefix() {
+        let route = route(vec![
+            http_route(None, "/", false),
+            http_route(None, "/api", false),
+            http_route(None, "/api/v1", false),
+            http_route(Some("example.com"), "/", false),
+            http_route(None, "/api", true),
+        ]);
+        assert_eq!(
+            picked(&route, Some("example.com"), "/api/v1/x"),
+            Some((Some("example.com"), "/"))
+        );
+        assert_eq!(picked(&route, None, "/api/v1/x"), Some((None, "/api/v1")));
+        assert_eq!(picked(&route, None, "/apix"), Some((None, "/")));
+        // 一样具体时用先配置的
+        let http = pick(&route, None, "/api/x").unwrap();
+        assert_eq!(http.path, "/api");
+        assert!(!http.strip_prefix);
+    }
+
+    #[test]
+    fn pick_should_return_none_without_match() {
+        let api = route(vec![http_route(None, "/api", false)]);
+        assert!(pick(&api, None, "/other").is_none());
+        assert!(pick(&route(vec![]), None, "/").is_none());
+    }
+
+    #[test]
+    fn uses_should_only_match_current_pools() {
+        let api = http_route(None, "/api", false);
+        let route = route(vec![api.clone()]);
+        assert!(uses(&route, &route.pool));
+        assert!(uses(&route, &api.pool));
+        // reload 之后换掉的组
+        assert!(!uses(&route, &pool()));
+    }
+
+    #[test]
+    fn strip_prefix_should_keep_rest_and_query() {
+        assert_eq!(stripped("/api/users/1", "/api"), "/users/1");
+        assert_eq!(stripped("/api/users?id=1", "/api/"), "/users?id=1");
+        // 什么都不剩或者只剩 query 时补上 /
+        assert_eq!(stripped("/api", "/api"), "/");
+        assert_eq!(stripped("/api?x=1", "/api"), "/?x=1");
+        assert_eq!(stripped("/api/", "/api"), "/");
+        assert_eq!(stripped("/users", "/"), "/users");
+    }
+}
@@ -0,0 +1,269 @@
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::time::{interval, MissedTickBehavior};
+
+/// 所有 listener 共用, 0 表示不限制
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    // 加起来最多同时多少个连接
+    pub max_connections: usize,
+    // 同一个 IP 最多同时多少个连接
+    pub max_connections_per_ip: usize,
+    // 令牌桶: 同一个 IP 每秒能建多少个新连接, 最多攒多少个
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    MaxConnections,
+    MaxConnectionsPerIp,
+    RateLimited,
+}
+
+/// 按 IP 记的状态, 没有连接并且桶满了的会被定时清掉
+#[derive(Debug)]
+struct Client {
+    connections: usize,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Debug)]
+pub struct Limiter {
+    config: RwLock<LimitConfig>,
+    connections: AtomicUsize,
+    clients: DashMap<IpAddr, Client>,
+    // 按 Rejection 的顺序, 每种原因一共拒绝了多少个
+    rejected: [AtomicU64; 3],
+}
+
+/// 连接结束 drop 时把计数减回去
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            rate: 0.0,
+            burst: 10,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MaxConnections => write!(f, "too many connections"),
+            Self::MaxConnectionsPerIp => write!(f, "too many connections from this IP"),
+            Self::RateLimited => write!(f, "too many new connections from this IP"),
+        }
+    }
+}
+
+impl Client {
+    fn refill(&mut self, config: &LimitConfig, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate).min(f64::from(config.burst));
+        self.last_refill = now;
+    }
+}
+
+impl Limiter {
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            connections: AtomicUsize::new(0),
+            clients: DashMap::new(),
+            rejected: Default::default(),
+        }
+    }
+
+    /// reload 以后的新连接按新的配置算, 已有的连接不受影响
+    pub fn update(&self, config: LimitConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// 超过任何一个限制都直接拒绝, 不会排队
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Permit, Rejection> {
+        let config = *self.config.read().unwrap();
+        let result = self.try_admit(ip, &config);
+        if let Err(rejection) = result {
+            self.rejected[rejection as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn try_admit(self: &Arc<Self>, ip: IpAddr, config: &LimitConfig) -> Result<Permit, Rejection> {
+        let now = Instant::now();
+        let mut client = self.clients.entry(ip).or_insert_with(|| Client {
+            connections: 0,
+            tokens: f64::from(config.burst),
+            last_refill: now,
+        });
+        if config.max_connections_per_ip > 0 && client.connections >= config.max_connections_per_ip
+        {
+            return Err(Rejection::MaxConnectionsPerIp);
+        }
+        if config.rate > 0.0 {
+            client.refill(config, now);
+            if client.tokens < 1.0 {
+                return Err(Rejection::RateLimited);
+            }
+        }
+        let connections = self.connections.fetch_add(1, Ordering::Relaxed);
+        if config.max_connections > 0 && connections >= config.max_connections {
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+            return Err(Rejection::MaxConnections);
+        }
+        // 真的接受了才用掉 token, 被其他限制拒绝的不算
+        if config.rate > 0.0 {
+            client.tokens -= 1.0;
+        }
+        client.connections += 1;
+        Ok(Permit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn rejected(&self, rejection: Rejection) -> u64 {
+        self.rejected[rejection as usize].load(Ordering::Relaxed)
+    }
+
+    /// 定时清掉已经没有连接, 桶也满了的 IP, 不然每个来过的 IP 都会一直留着
+    pub async fn sweep(self: Arc<Self>, period: Duration) {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let config = *self.config.read().unwrap();
+            let now = Instant::now();
+            self.clients.retain(|_, client| {
+                client.refill(&config, now);
+                client.connections > 0 || client.tokens < f64::from(config.burst)
+            });
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.connections.fetch_sub(1, Ordering::Relaxed);
+        if let Some(mut client) = self.limiter.clients.get_mut(&self.ip) {
+            client.connections -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    fn limiter(config: LimitConfig) -> Arc<Limiter> {
+        Arc::new(Limiter::new(config))
+    }
+
+    // 补得很慢, 测试期间不会补上 token
+    fn rate(burst: u32) -> LimitConfig {
+        LimitConfig {
+            rate: 0.001,
+            burst,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_should_not_limit() {
+        let limiter = limiter(LimitConfig::default());
+        let permits: Vec<_> = (0..100).map(|_| limiter.admit(A).unwrap()).collect();
+        assert_eq!(permits.len(), 100);
+    }
+
+    #[test]
+    fn per_ip_limit_should_count_open_connections() {
+        let limiter = limiter(LimitConfig {
+            max_connections_per_ip: 2,
+            ..Default::default()
+        });
+        let first = limiter.admit(A).unwrap();
+        let _second = limiter.admit(A).unwrap();
+        assert_eq!(
+            limiter.admit(A).unwrap_err(),
+            Rejection::MaxConnectionsPerIp
+        );
+        assert!(limiter.admit(B).is_ok());
+        drop(first);
+        assert!(limiter.admit(A).is_ok());
+        assert_eq!(limiter.rejected(Rejection::MaxConnectionsPerIp), 1);
+    }
+
+    #[test]
+    fn global_limit_should_count_all_ips() {
+        let limiter = limiter(LimitConfig {
+            max_connections: 2,
+            ..Default::default()
+        });
+        let first = limiter.admit(A).unwrap();
+        let _second = limiter.admit(B).unwrap();
+        assert_eq!(limiter.admit(A).unwrap_err(), Rejection::MaxConnections);
+        drop(first);
+        assert!(limiter.admit(B).is_ok());
+        assert_eq!(limiter.rejected(Rejection::MaxConnections), 1);
+    }
+
+    #[test]
+    fn rate_limit_should_allow_burst_per_ip() {
+        let limiter = limiter(rate(2));
+        assert!(limiter.admit(A).is_ok());
+        assert!(limiter.admit(A).is_ok());
+        assert_eq!(limiter.admit(A).unwrap_err(), Rejection::RateLimited);
+        assert!(limiter.admit(B).is_ok());
+        assert_eq!(limiter.rejected(Rejection::RateLimited), 1);
+    }
+
+    #[test]
+    fn rejected_connections_should_not_take_tokens() {
+        let limiter = limiter(LimitConfig {
+            max_connections_per_ip: 1,
+            ..rate(2)
+        });
+        let first = limiter.admit(A).unwrap();
+        for _ in 0..5 {
+            assert_eq!(
+                limiter.admit(A).unwrap_err(),
+                Rejection::MaxConnectionsPerIp
+            );
+        }
+        drop(first);
+        // 还剩一个 token
+        let second = limiter.admit(A).unwrap();
+        drop(second);
+        assert_eq!(limiter.admit(A).unwrap_err(), Rejection::RateLimited);
+    }
+
+    #[test]
+    fn update_should_apply_to_new_connections() {
+        let limiter = limiter(LimitConfig::default());
+        let _first = limiter.admit(A).unwrap();
+        limiter.update(LimitConfig {
+            max_connections: 1,
+            ..Default::default()
+        });
+        assert_eq!(limiter.admit(A).unwrap_err(), Rejection::MaxConnections);
+    }
+}
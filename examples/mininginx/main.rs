@@ -0,0 +1,560 @@
+mod config;
+mod health;
+mod http;
+mod limiter;
+mod upstream;
+
+use crate::config::{Config, GroupConfig, ListenerConfig, Mode};
+use crate::http::HttpRoute;
+use crate::limiter::{Limiter, Rejection};
+use crate::upstream::{Lease, Pool};
+use anyhow::{anyhow, Context, Result};
+use ecosystem::shutdown;
+use ecosystem::tls::TlsAcceptor;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, Layer};
+
+// 多久检查一次证书文件有没有更新
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+// 多久检查一次配置文件有没有更新
+const CONFIG_RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+// 多久清理一次不再活跃的客户端 IP
+const LIMITER_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 新连接转发到哪里, reload 时整个换掉, 已经建立的 tcp 连接不受影响
+#[derive(Debug, Clone)]
+struct Route {
+    mode: Mode,
+    // 默认的 upstream 组
+    pool: Arc<Pool>,
+    connect_timeout: Duration,
+    // http 模式按 host 和 path 选组
+    http: Vec<HttpRoute>,
+    // 客户端到 proxy 这一段的 TLS, 配置变了 reload 时直接换, 不用重新 listen
+    tls: Option<Arc<TlsAcceptor>>,
+    // 放在 X-Forwarded-Proto 里
+    proto: &'static str,
+}
+
+/// 一个正在 accept 的 listener
+struct Running {
+    config: ListenerConfig,
+    route: watch::Sender<Route>,
+    stop: CancellationToken,
+    task: JoinHandle<()>,
+    // 定时检查证书文件有没有更新, TLS 配置变了时换掉
+    tls_watcher: Option<JoinHandle<()>>,
+}
+
+/// 按配置启动/停止 listener, 所有连接都在同一个 TaskTracker 里, 退出时一起 drain
+struct Proxy {
+    listeners: HashMap<SocketAddr, Running>,
+    // 组名 -> (配置, pool), 配置没变的组 reload 时继续用, 健康状态和连接数不会丢
+    pools: HashMap<String, (GroupConfig, Arc<Pool>)>,
+    connections: TaskTracker,
+    // 连接数和新连接速率的限制, 所有 listener 共用
+    limiter: Arc<Limiter>,
+    shutdown: CancellationToken,
+}
+
+impl Proxy {
+    fn new(shutdown: CancellationToken) -> Self {
+        Self {
+            listeners: HashMap::new(),
+            pools: HashMap::new(),
+            connections: TaskTracker::new(),
+            limiter: Arc::new(Limiter::new(Default::default())),
+            shutdown,
+        }
+    }
+
+    /// 切换到新的配置. 返回 Err 时什么都没改, 继续用原来的配置
+    async fn apply(&mut self, config: &Config) -> Result<()> {
+        let mut pools = HashMap::new();
+        for (name, group) in &config.upstreams {
+            let pool = match self.pools.get(name) {
+                Some((old, pool)) if old == group => pool.clone(),
+                _ => {
+                    let pool = Arc::new(Pool::new(
+                        &group.servers,
+                        group.strategy,
+                        group.health.clone(),
+                    ));
+                    for upstream in pool.upstreams() {
+                        info!(
+                            "Upstream {} in {} with weight {}",
+                            upstream.addr, name, upstream.weight
+                        );
+                    }
+                    info!("Balancing {} with {:?}", name, group.strategy);
+                    tokio::spawn(health::watch(Arc::downgrade(&pool)));
+                    pool
+                }
+            };
+            pools.insert(name.clone(), (group.clone(), pool));
+        }
+
+        // 会出错的事情先做: 绑定新的地址, 加载新的证书; 出错时原来的 listener 都还在
+        let mut sockets = HashMap::new();
+        let mut acceptors = HashMap::new();
+        for listener in &config.listeners {
+            let running = self.listeners.get(&listener.listen);
+            if running.is_none() {
+                let socket = TcpListener::bind(listener.listen)
+                    .await
+                    .with_context(|| format!("failed to listen on {}", listener.listen))?;
+                sockets.insert(listener.listen, socket);
+            }
+            let tls_changed = running.is_none_or(|running| running.config.tls != listener.tls);
+            if let (true, Some(tls)) = (tls_changed, &listener.tls) {
+                acceptors.insert(listener.listen, Arc::new(TlsAcceptor::new(tls.clone())?));
+            }
+        }
+
+        // 下面不会再出错了
+        let removed: Vec<_> = self
+            .listeners
+            .keys()
+            .filter(|addr| !config.listeners.iter().any(|l| l.listen == **addr))
+            .copied()
+            .collect();
+        for addr in removed {
+            if let Some(running) = self.listeners.remove(&addr) {
+                running.stop().await;
+                info!("Stopped listening on {}", addr);
+            }
+        }
+
+        for listener in &config.listeners {
+            let running = self.listeners.get_mut(&listener.listen);
+            let tls_changed = running
+                .as_ref()
+                .is_some_and(|running| running.config.tls != listener.tls);
+            let tls = match (acceptors.remove(&listener.listen), &running) {
+                (Some(acceptor), _) => Some(acceptor),
+                // TLS 配置没变时继续用原来的, 已经加载的证书不用重新读
+                (None, Some(running)) if !tls_changed => running.route.borrow().tls.clone(),
+                _ => None,
+            };
+            let route = Route {
+                mode: listener.mode,
+                pool: pools[&listener.upstream].1.clone(),
+                connect_timeout: listener.connect_timeout,
+                http: listener
+                    .routes
+                    .iter()
+                    .map(|route| HttpRoute::new(route, pools[&route.upstream].1.clone()))
+                    .collect(),
+                proto: match tls {
+                    Some(_) => "https",
+                    None => "http",
+                },
+                tls,
+            };
+            let Some(running) = running else {
+                // 新的地址上面已经绑定过了
+                if let Some(socket) = sockets.remove(&listener.listen) {
+                    self.start(listener.clone(), socket, route);
+                }
+                continue;
+            };
+            if tls_changed {
+                if let Some(watcher) = running.tls_watcher.take() {
+                    watcher.abort();
+                }
+                running.tls_watcher = route.tls.clone().map(watch_tls);
+                info!("Switched TLS settings on {}", listener.listen);
+            }
+            running.route.send_replace(route);
+            running.config = listener.clone();
+        }
+        self.pools = pools;
+        self.limiter.update(config.limits);
+        Ok(())
+    }
+
+    fn start(&mut self, config: ListenerConfig, socket: TcpListener, route: Route) {
+        let tls_watcher = route.tls.clone().map(watch_tls);
+        let (route, routes) = watch::channel(route);
+        let stop = self.shutdown.child_token();
+        let connections = self.connections.clone();
+        let limiter = self.limiter.clone();
+        let task = tokio::spawn(serve(socket, routes, connections, limiter, stop.clone()));
+        info!(
+            "Listening on {} for {} in {:?} mode",
+            config.listen, config.upstream, config.mode
+        );
+        self.listeners.insert(
+            config.listen,
+            Running {
+                config,
+                route,
+                stop,
+                task,
+                tls_watcher,
+            },
+        );
+    }
+
+    /// 停掉所有 listener, 已经建立的连接还在 connections 里
+    async fn stop(&mut self) {
+        for (_, running) in self.listeners.drain() {
+            running.stop().await;
+        }
+    }
+}
+
+impl Running {
+    async fn stop(self) {
+        self.stop.cancel();
+        let _ = self.task.await;
+        if let Some(watcher) = self.tls_watcher {
+            watcher.abort();
+        }
+    }
+}
+
+fn watch_tls(acceptor: Arc<TlsAcceptor>) -> JoinHandle<()> {
+    tokio::spawn(acceptor.watch(TLS_RELOAD_INTERVAL))
+}
+
+//
+// #[tokio::main]
+// async fn main() -> Result<()> {
+//     let config = Config::default();
+//     let config = Arc::new(config);
+//     let layer = fmt::Layer::new().with_filter(LevelFilter::INFO);
+//     tracing_subscriber::registry().with(layer).init();
+//     info!("listening on {}", config.listen_addr);
+//     info!("upstream is {}", config.upstream_addr);
+//
+//     // 创建tcp
+//     let listener = TcpListener::bind(&config.listen_addr).await?;
+//     loop {
+//         let (client, addr) = listener.accept().await?;
+//         info!("accepted connection from {}", addr);
+//         let cloned_config = config.clone();
+//         tokio::spawn(async move {
+//             let upstream = TcpStream::connect(&cloned_config.upstream_addr).await?;
+//             proxy(client, upstream).await?;
+//             Ok::<(), anyhow::Error>(())
+//         });
+//     }
+//     #[allow(unreachable_code)]
+//     Ok(())
+// }
+//
+// async fn proxy(mut client: TcpStream, mut upstream: TcpStream) -> Result<()> {
+//     let (mut client_reader, mut client_writer) = client.split();
+//     let (mut upstream_reader, mut upstream_writer) = upstream.split();
+//
+//     let client_to_upstream = tokio::io::copy(&mut client_reader, &mut upstream_writer);
+//     let upstream_to_client = tokio::io::copy(&mut upstream_reader, &mut client_writer);
+//     match tokio::try_join!(client_to_upstream, upstream_to_client) {
+//         Ok((n, m)) => info!(
+//             "proxied {} bytes from client to upstream, {} bytes from upstream to client",
+//             n, m
+//         ),
+//         Err(e) => warn!("error proxying: {:?}", e),
+//     }
+//     Ok(())
+// }
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = fmt::Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+    // 设置了 MININGINX_CONFIG 就从这个文件读, 文件有变化或者收到 SIGHUP 时 reload
+    let path = std::env::var("MININGINX_CONFIG").ok().map(PathBuf::from);
+    let mut config = match &path {
+        Some(path) => Config::load(path)?,
+        None => Config::from_env()?,
+    };
+    let shutdown = shutdown::on_signal();
+    let mut proxy = Proxy::new(shutdown.clone());
+    proxy.apply(&config).await?;
+    tokio::spawn(proxy.limiter.clone().sweep(LIMITER_SWEEP_INTERVAL));
+
+    let (reload, mut reloads) = mpsc::channel(1);
+    if let Some(path) = &path {
+        info!("Watching config file {}", path.display());
+        tokio::spawn(watch_file(path.clone(), reload.clone()));
+    }
+    tokio::spawn(on_hangup(reload));
+    loop {
+        let reason = tokio::select! {
+            Some(reason) = reloads.recv() => reason,
+            _ = shutdown.cancelled() => break,
+        };
+        let Some(path) = &path else {
+            info!("Received {}, but there is no config file to reload", reason);
+            continue;
+        };
+        let reloaded = match Config::load(path) {
+            Ok(new) => proxy.apply(&new).await.map(|_| new),
+            Err(e) => Err(e),
+        };
+        match reloaded {
+            Ok(new) => {
+                info!("Reloaded config from {} on {}", path.display(), reason);
+                config = new;
+            }
+            Err(e) => warn!(
+                "Failed to reload config on {}, keep using the last good one: {:#}",
+                reason, e
+            ),
+        }
+    }
+
+    // 不再接受新连接, 已有的连接最多再转发 drain_timeout
+    proxy.stop().await;
+    let limiter = &proxy.limiter;
+    let rejected = [
+        Rejection::MaxConnections,
+        Rejection::MaxConnectionsPerIp,
+        Rejection::RateLimited,
+    ]
+    .map(|rejection| limiter.rejected(rejection));
+    if rejected.iter().any(|&n| n > 0) {
+        info!(
+            "Rejected {} connections over max_connections, {} over max_connections_per_ip, {} over rate limit",
+            rejected[0], rejected[1], rejected[2]
+        );
+    }
+    let connections = proxy.connections;
+    connections.close();
+    info!(
+        "Draining {} connections for at most {:?}",
+        connections.len(),
+        config.drain_timeout
+    );
+    if timeout(config.drain_timeout, connections.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Dropping {} connections after {:?}",
+            connections.len(),
+            config.drain_timeout
+        );
+    }
+    Ok(())
+}
+
+async fn serve(
+    listener: TcpListener,
+    routes: watch::Receiver<Route>,
+    connections: TaskTracker,
+    limiter: Arc<Limiter>,
+    stop: CancellationToken,
+) {
+    loop {
+        let (client, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // 一般是文件描述符用完了, 等一会再试
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = stop.cancelled() => return,
+        };
+        // 超限的连接直接关掉, 不占用任务, 也不用做 TLS 握手
+        let permit = match limiter.admit(addr.ip()) {
+            Ok(permit) => permit,
+            Err(rejection) => {
+                warn!(
+                    "Rejected connection from {}: {} ({} so far)",
+                    addr,
+                    rejection,
+                    limiter.rejected(rejection)
+                );
+                continue;
+            }
+        };
+        info!("Accepted connection from {}", addr);
+        let route = routes.borrow().clone();
+        let routes = routes.clone();
+        let stop = stop.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            // 握手放在连接自己的任务里, 握手慢的连接不会挡住 accept
+            let Some(acceptor) = route.tls.clone() else {
+                return handle(client, addr, route, routes, stop).await;
+            };
+            match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(client)).await {
+                Ok(Ok(client)) => return handle(client, addr, route, routes, stop).await,
+                Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", addr, e),
+                Err(_) => warn!("TLS handshake with {} timed out", addr),
+            }
+            Ok(())
+        });
+    }
+}
+
+async fn handle<S>(
+    client: S,
+    addr: SocketAddr,
+    route: Route,
+    routes: watch::Receiver<Route>,
+    stop: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if route.mode == Mode::Http {
+        http::serve_connection(client, addr, routes, stop).await;
+        return Ok(());
+    }
+    // lease 活到连接结束, least-conn 才知道真实的连接数
+    let connected = connect(&route.pool, addr, route.connect_timeout).await;
+    // 连上以后不再拿着 pool, reload 换掉的 pool 可以释放
+    drop(route);
+    let Some((lease, upstream)) = connected else {
+        warn!("No healthy upstream for {}, dropping connection", addr);
+        return Ok(());
+    };
+    info!(
+        "Proxying {} to {} ({} active)",
+        addr,
+        lease.addr,
+        lease.active()
+    );
+    proxy(client, upstream).await?;
+    drop(lease);
+    Ok(())
+}
+
+/// 定时看配置文件的修改时间, 变了就触发 reload
+async fn watch_file(path: PathBuf, reload: mpsc::Sender<&'static str>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last = modified(&path);
+    let mut ticker = interval(CONFIG_RELOAD_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let current = modified(&path);
+        if current != last {
+            last = current;
+            // 已经有一次 reload 在排队时不用再排
+            let _ = reload.try_send("file change");
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn on_hangup(reload: mpsc::Sender<&'static str>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let _ = reload.try_send("SIGHUP");
+    }
+}
+
+#[cfg(not(unix))]
+async fn on_hangup(_reload: mpsc::Sender<&'static str>) {}
+
+/// 选一个 upstream 连上, 连不上就记一次失败再换一个, 每个 upstream 最多试一次.
+/// 试过的要排除掉, 不然 ip-hash 和 least-conn 在摘掉之前会一直选同一个
+async fn connect(
+    pool: &Pool,
+    client: SocketAddr,
+    connect_timeout: Duration,
+) -> Option<(Lease, TcpStream)> {
+    let mut tried = Vec::new();
+    for _ in 0..pool.upstreams().len() {
+        let lease = pool.pick(client.ip(), &tried)?;
+        let result = match timeout(connect_timeout, TcpStream::connect(&lease.addr)).await {
+            Ok(result) => result.map_err(anyhow::Error::from),
+            Err(_) => Err(anyhow!("timed out after {:?}", connect_timeout)),
+        };
+        pool.connected(&lease, result.is_ok());
+        match result {
+            Ok(upstream) => return Some((lease, upstream)),
+            Err(e) => {
+                warn!(
+                    "Failed to connect to upstream {} for {}: {:#}",
+                    lease.addr, client, e
+                );
+                tried.push(lease.upstream().clone());
+            }
+        }
+    }
+    None
+}
+
+async fn proxy<S>(mut client: S, mut upstream: TcpStream) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // 一边读到 EOF 时关掉另一边的写, 不然 upstream 一直等不到客户端断开, 连接数也减不下来
+    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((n, m)) => info!(
+            "proxied {} bytes from client to upstream, {} bytes from upstream to client",
+            n, m
+        ),
+        Err(e) => warn!("error proxying: {:?}", e),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthConfig;
+    use crate::upstream::{Strategy, UpstreamConfig};
+
+    #[tokio::test]
+    async fn connect_should_try_another_upstream_with_ip_hash() {
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // 绑定之后马上关掉, 这个端口会拒绝连接
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_addr = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let servers = [
+            UpstreamConfig {
+                addr: dead_addr.clone(),
+                weight: 1,
+            },
+            UpstreamConfig {
+                addr: live.local_addr().unwrap().to_string(),
+                weight: 1,
+            },
+        ];
+        let pool = Pool::new(&servers, Strategy::IpHash, HealthConfig::default());
+        // 找一个固定分到坏掉的 upstream 的客户端
+        let client = (1..=255u8)
+            .map(|n| SocketAddr::from(([10, 0, 0, n], 1234)))
+            .find(|client| pool.pick(client.ip(), &[]).unwrap().addr == dead_addr)
+            .unwrap();
+        let (lease, _) = connect(&pool, client, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_ne!(lease.addr, dead_addr);
+    }
+}
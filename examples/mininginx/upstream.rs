@@ -0,0 +1,403 @@
+use crate::health::{Health, HealthConfig};
+use anyhow::{anyhow, Result};
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// 一致性 hash 环上每份权重放多少个虚拟节点, 越多分布越均匀
+const VIRTUAL_NODES: u32 = 160;
+// 权重太大时 hash 环会很大
+const MAX_WEIGHT: u32 = 100;
+
+/// 新连接选哪个 upstream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    // 平滑加权轮询, 和 nginx 一样
+    WeightedRoundRobin,
+    // 活跃连接数 / 权重 最小的
+    LeastConnections,
+    // 按客户端 IP 做一致性 hash, 同一个 IP 总是到同一个 upstream
+    IpHash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamConfig {
+    pub addr: String,
+    pub weight: u32,
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub addr: String,
+    pub weight: u32,
+    // 正在转发的连接数
+    active: AtomicUsize,
+    pub health: Mutex<Health>,
+}
+
+/// 选中的 upstream, 连接结束 drop 时活跃连接数减一
+#[derive(Debug)]
+pub struct Lease {
+    upstream: Arc<Upstream>,
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: Strategy,
+    health: HealthConfig,
+    // 轮询的下一个位置
+    next: AtomicUsize,
+    // 平滑加权轮询每个 upstream 当前的权重
+    current: Mutex<Vec<i64>>,
+    // (hash, upstream 下标), 按 hash 排好序
+    ring: Vec<(u64, usize)>,
+}
+
+/// `round-robin`, `weighted`, `least-conn` 或者 `ip-hash`
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "weighted" => Ok(Self::WeightedRoundRobin),
+            "least-conn" => Ok(Self::LeastConnections),
+            "ip-hash" => Ok(Self::IpHash),
+            _ => Err(anyhow!(
+                "invalid strategy {}, expect round-robin, weighted, least-conn or ip-hash",
+                s
+            )),
+        }
+    }
+}
+
+/// `host:port` 或者 `host:port=权重`, 默认权重是 1, 最大 100
+impl FromStr for UpstreamConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, weight) = match s.rsplit_once('=') {
+            Some((addr, weight)) => (addr, weight.parse()?),
+            None => (s, 1),
+        };
+        if addr.is_empty() || !(1..=MAX_WEIGHT).contains(&weight) {
+            return Err(anyhow!(
+                "invalid upstream {}, expect host:port or host:port=<weight>, weight 1..={}",
+                s,
+                MAX_WEIGHT
+            ));
+        }
+        Ok(Self {
+            addr: addr.to_string(),
+            weight,
+        })
+    }
+}
+
+impl Upstream {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+impl Lease {
+    pub fn upstream(&self) -> &Arc<Upstream> {
+        &self.upstream
+    }
+}
+
+impl Deref for Lease {
+    type Target = Upstream;
+
+    fn deref(&self) -> &Upstream {
+        &self.upstream
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Pool {
+    pub fn new(upstreams: &[UpstreamConfig], strategy: Strategy, health: HealthConfig) -> Self {
+        let upstreams: Vec<_> = upstreams
+            .iter()
+            .map(|upstream| {
+                Arc::new(Upstream {
+                    addr: upstream.addr.clone(),
+                    weight: upstream.weight,
+                    active: AtomicUsize::new(0),
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect();
+        let mut ring = Vec::new();
+        if strategy == Strategy::IpHash {
+            for (i, upstream) in upstreams.iter().enumerate() {
+                for n in 0..VIRTUAL_NODES * upstream.weight {
+                    ring.push((hash(format!("{}#{}", upstream.addr, n).as_bytes()), i));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Self {
+            current: Mutex::new(vec![0; upstreams.len()]),
+            upstreams,
+            strategy,
+            health,
+            next: AtomicUsize::new(0),
+            ring,
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    pub fn health(&self) -> &HealthConfig {
+        &self.health
+    }
+
+    /// 给 client 选一个 upstream, 跳过不健康的和 tried 里已经试过的, 一个都没有时返回 None
+    pub fn pick(&self, client: IpAddr, tried: &[Arc<Upstream>]) -> Option<Lease> {
+        let available: Vec<bool> = self
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                !tried.iter().any(|tried| Arc::ptr_eq(tried, upstream))
+                    && upstream.health.lock().unwrap().available(&upstream.addr)
+            })
+            .collect();
+        if !available.contains(&true) {
+            return None;
+        }
+        let i = match self.strategy {
+            Strategy::RoundRobin => self.round_robin(&available),
+            Strategy::WeightedRoundRobin => self.weighted(&available),
+            Strategy::LeastConnections => self.least_connections(&available),
+            Strategy::IpHash => self.ip_hash(client, &available),
+        };
+        let upstream = self.upstreams[i].clone();
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        Some(Lease { upstream })
+    }
+
+    /// 转发时连 upstream 的结果, 用来做被动检查
+    pub fn connected(&self, upstream: &Upstream, ok: bool) {
+        upstream
+            .health
+            .lock()
+            .unwrap()
+            .connected(&upstream.addr, ok, &self.health);
+    }
+
+    /// 从下一个位置开始, 第一个可用的
+    fn rotation<'a>(&self, available: &'a [bool]) -> impl Iterator<Item = usize> + 'a {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = available.len();
+        (0..len)
+            .map(move |n| (start + n) % len)
+            .filter(|&i| available[i])
+    }
+
+    fn round_robin(&self, available: &[bool]) -> usize {
+        // 在可用的里面轮, 不然挂掉的那个的份会全落到它后面那个上
+        let available: Vec<usize> = (0..available.len()).filter(|&i| available[i]).collect();
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        available[n % available.len()]
+    }
+
+    fn weighted(&self, available: &[bool]) -> usize {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0;
+        let mut best = None;
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            if !available[i] {
+                continue;
+            }
+            total += upstream.weight as i64;
+            current[i] += upstream.weight as i64;
+            if best.is_none_or(|best| current[i] > current[best]) {
+                best = Some(i);
+            }
+        }
+        let best = best.unwrap_or_default();
+        current[best] -= total;
+        best
+    }
+
+    fn least_connections(&self, available: &[bool]) -> usize {
+        // 从轮询的位置开始找, 连接数一样时不会总是选第一个
+        self.rotation(available)
+            .min_by(|&a, &b| {
+                // active_a / weight_a < active_b / weight_b, 交叉相乘避免浮点
+                let a = &self.upstreams[a];
+                let b = &self.upstreams[b];
+                (a.active() as u64 * b.weight as u64).cmp(&(b.active() as u64 * a.weight as u64))
+            })
+            .unwrap_or_default()
+    }
+
+    fn ip_hash(&self, client: IpAddr, available: &[bool]) -> usize {
+        let key = match client {
+            IpAddr::V4(ip) => hash(&ip.octets()),
+            IpAddr::V6(ip) => hash(&ip.octets()),
+        };
+        // 环上第一个 >= key 并且可用的节点, 超过最后一个就绕回开头,
+        // 一个 upstream 挂了只有它的客户端会换地方
+        let pos = self.ring.partition_point(|(h, _)| *h < key);
+        (0..self.ring.len())
+            .map(|n| self.ring[(pos + n) % self.ring.len()].1)
+            .find(|&i| available[i])
+            .unwrap_or_default()
+    }
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let hash = blake3::hash(data);
+    u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn pool(servers: &[&str], strategy: Strategy) -> Pool {
+        let servers: Vec<UpstreamConfig> = servers.iter().map(|s| s.parse().unwrap()).collect();
+        // 连不上一次就摘掉, 方便测试跳过不可用的
+        let health = HealthConfig {
+            max_fails: 1,
+            ..Default::default()
+        };
+        Pool::new(&servers, strategy, health)
+    }
+
+    fn picks(pool: &Pool, n: usize) -> Vec<String> {
+        (0..n)
+            .map(|_| pool.pick(CLIENT, &[]).unwrap().addr.clone())
+            .collect()
+    }
+
+    fn eject(pool: &Pool, i: usize) {
+        pool.connected(&pool.upstreams()[i], false);
+    }
+
+    #[test]
+    fn upstream_config_should_parse_weight() {
+        let upstream: UpstreamConfig = "127.0.0.1:80=3".parse().unwrap();
+        assert_eq!(upstream.addr, "127.0.0.1:80");
+        assert_eq!(upstream.weight, 3);
+        assert_eq!("a:1".parse::<UpstreamConfig>().unwrap().weight, 1);
+        assert!("a:1=0".parse::<UpstreamConfig>().is_err());
+        assert!("a:1=101".parse::<UpstreamConfig>().is_err());
+        assert!("a:1=4294967295".parse::<UpstreamConfig>().is_err());
+        assert!("=2".parse::<UpstreamConfig>().is_err());
+    }
+
+    #[test]
+    fn round_robin_should_skip_unavailable() {
+        let pool = pool(&["a:1", "b:1", "c:1"], Strategy::RoundRobin);
+        assert_eq!(picks(&pool, 4), ["a:1", "b:1", "c:1", "a:1"]);
+        eject(&pool, 1);
+        // b 的份平均分给 a 和 c
+        assert_eq!(picks(&pool, 4), ["a:1", "c:1", "a:1", "c:1"]);
+    }
+
+    #[test]
+    fn weighted_should_be_smooth() {
+        let pool = pool(&["a:1=5", "b:1", "c:1"], Strategy::WeightedRoundRobin);
+        // 和 nginx 的顺序一样, 不会连着选 5 次 a
+        assert_eq!(
+            picks(&pool, 7),
+            ["a:1", "a:1", "b:1", "a:1", "c:1", "a:1", "a:1"]
+        );
+        assert_eq!(picks(&pool, 7), picks(&pool, 7));
+    }
+
+    #[test]
+    fn weighted_should_skip_unavailable() {
+        let pool = pool(&["a:1=2", "b:1", "c:1=2"], Strategy::WeightedRoundRobin);
+        eject(&pool, 0);
+        let mut counts = HashMap::new();
+        for addr in picks(&pool, 30) {
+            *counts.entry(addr).or_insert(0) += 1;
+        }
+        assert_eq!(counts.get("a:1"), None);
+        assert_eq!(counts["b:1"], 10);
+        assert_eq!(counts["c:1"], 20);
+    }
+
+    #[test]
+    fn least_connections_should_count_leases() {
+        let pool = pool(&["a:1=2", "b:1"], Strategy::LeastConnections);
+        let leases: Vec<_> = (0..3).map(|_| pool.pick(CLIENT, &[]).unwrap()).collect();
+        // a 的权重是 2, 3 个连接里分到 2 个
+        assert_eq!(pool.upstreams()[0].active(), 2);
+        assert_eq!(pool.upstreams()[1].active(), 1);
+        drop(leases);
+        assert_eq!(pool.upstreams()[0].active(), 0);
+        assert_eq!(pool.upstreams()[1].active(), 0);
+    }
+
+    #[test]
+    fn ip_hash_should_be_sticky() {
+        let pool = pool(&["a:1", "b:1", "c:1"], Strategy::IpHash);
+        let clients: Vec<IpAddr> = (0..64u8)
+            .map(|n| IpAddr::V4([10, 0, 0, n].into()))
+            .collect();
+        let before: Vec<String> = clients
+            .iter()
+            .map(|&ip| pool.pick(ip, &[]).unwrap().addr.clone())
+            .collect();
+        for (ip, addr) in clients.iter().zip(&before) {
+            assert_eq!(&pool.pick(*ip, &[]).unwrap().addr, addr);
+        }
+        // 每个 upstream 都分到一些客户端
+        for addr in ["a:1", "b:1", "c:1"] {
+            assert!(before.iter().any(|a| a == addr));
+        }
+        // 摘掉 b 之后只有 b 的客户端换地方
+        eject(&pool, 1);
+        for (ip, addr) in clients.iter().zip(&before) {
+            let now = pool.pick(*ip, &[]).unwrap().addr.clone();
+            match addr.as_str() {
+                "b:1" => assert_ne!(now, "b:1"),
+                _ => assert_eq!(&now, addr),
+            }
+        }
+    }
+
+    #[test]
+    fn pick_should_skip_tried() {
+        for strategy in [Strategy::IpHash, Strategy::LeastConnections] {
+            let pool = pool(&["a:1", "b:1", "c:1"], strategy);
+            let first = pool.pick(CLIENT, &[]).unwrap();
+            let tried = vec![first.upstream().clone()];
+            drop(first);
+            let second = pool.pick(CLIENT, &tried).unwrap();
+            assert_ne!(second.addr, tried[0].addr);
+            let tried = [tried[0].clone(), second.upstream().clone()];
+            let third = pool.pick(CLIENT, &tried).unwrap();
+            assert!(tried.iter().all(|tried| tried.addr != third.addr));
+            assert!(pool.pick(CLIENT, pool.upstreams()).is_none());
+        }
+    }
+
+    #[test]
+    fn pick_should_fail_when_all_unavailable() {
+        let pool = pool(&["a:1", "b:1"], Strategy::RoundRobin);
+        eject(&pool, 0);
+        eject(&pool, 1);
+        assert!(pool.pick(CLIENT, &[]).is_none());
+    }
+}
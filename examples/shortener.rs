@@ -1,21 +1,18 @@
 use anyhow::Result;
-use axum::extract::Path;
+use axum::extract::{Path, State};
 use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{Json, Router};
-use log::info;
+use ecosystem::shutdown;
 use nanoid::nanoid;
-use serde::Serialize;
-use sqlx::postgres::PgPoolOptions;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use thiserror::Error;
 use tokio::net::TcpListener;
 use tracing::level_filters::LevelFilter;
-use tracing::log::info;
-use tracing::warn;
+use tracing::{info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, Layer};
@@ -33,7 +30,7 @@ impl AppState {
             r#"
             CREATE TABLE IF NOT EXISTS urls (
                 id CHAR(6) PRIMARY KEY,
-                url TEXT NOT NULL UNIQUE,
+                url TEXT NOT NULL UNIQUE
             );
             "#,
         )
@@ -76,16 +73,22 @@ async fn main() -> Result<()> {
 
     let app = Router::new()
         .route("/", post(shorten))
-        .route("/:id", get(redirect))
+        .route("/{id}", get(redirect))
         .with_state(state);
     info!("server starting at {}", addr);
-    if let Err(e) = axum::serve::serve(listener, app.into_make_service()).await? {
+    // 收到 SIGINT/SIGTERM 后不再接受新连接, 处理完正在处理的请求再退出
+    let shutdown = shutdown::on_signal();
+    if let Err(e) = axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+    {
         warn!("server exited with error: {}", e);
     }
+    info!("server stopped");
     Ok(())
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize)]
 struct ShortenReq {
     url: String,
 }
@@ -97,7 +100,7 @@ struct ShortenRes {
 
 /// state 的 destruct 要放在最前面
 async fn shorten(
-    state: AppState,
+    State(state): State<Arc<AppState>>,
     Json(data): Json<ShortenReq>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let id = state
@@ -111,7 +114,7 @@ async fn shorten(
 }
 
 async fn redirect(
-    state: AppState,
+    State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let url = state.get(&id).await.map_err(|_| StatusCode::NOT_FOUND)?;
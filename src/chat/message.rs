@@ -0,0 +1,243 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+pub const SERVER_NAME: &str = "Server";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    Chat,
+    // /me
+    Action,
+    // /msg, 不属于任何房间
+    Private,
+    // 服务端发出的通知, sender 是 Server
+    System,
+    // sender 是加入/离开的人
+    Join,
+    Leave,
+    // 分享的文件, content 是文件名, 大小和下载用的 hash
+    File,
+    // 以下作用于 target 指向的消息, 客户端收到后更新自己的历史
+    // content 是修改后的内容
+    Edit,
+    Delete,
+    // content 是 emoji
+    React,
+    Unreact,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    // 服务端分配, 0 表示还没分配
+    #[serde(default)]
+    pub id: u64,
+    // None 表示只发给某个人的消息
+    pub room: Option<String>,
+    pub kind: MessageKind,
+    pub sender: String,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    // 历史消息回放时带上时间
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replayed: bool,
+    // 回复的是哪条消息
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    // Edit/Delete/React/Unreact 作用的消息
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<u64>,
+    // 谁发的, 登录了是账号, 游客是那次连接, 改名也不会变; 只在服务端检查权限用, 不发给客户端
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+}
+
+impl Message {
+    pub fn new(
+        kind: MessageKind,
+        room: Option<String>,
+        sender: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: 0,
+            room,
+            kind,
+            sender: sender.into(),
+            content: content.into(),
+            timestamp: Utc::now(),
+            replayed: false,
+            reply_to: None,
+            target: None,
+            author: None,
+        }
+    }
+
+    /// 作用于另一条消息的事件
+    fn on(
+        kind: MessageKind,
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        target: u64,
+        content: impl Into<String>,
+    ) -> Self {
+        Self {
+            target: Some(target),
+            ..Self::new(kind, Some(room.into()), sender, content)
+        }
+    }
+
+    pub fn chat(
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self::new(MessageKind::Chat, Some(room.into()), sender, content)
+    }
+
+    pub fn action(
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self::new(MessageKind::Action, Some(room.into()), sender, content)
+    }
+
+    pub fn system(room: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::new(MessageKind::System, Some(room.into()), SERVER_NAME, content)
+    }
+
+    pub fn join(room: impl Into<String>, username: impl Into<String>) -> Self {
+        Self::new(MessageKind::Join, Some(room.into()), username, "")
+    }
+
+    pub fn leave(room: impl Into<String>, username: impl Into<String>) -> Self {
+        Self::new(MessageKind::Leave, Some(room.into()), username, "")
+    }
+
+    pub fn file(
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self::new(MessageKind::File, Some(room.into()), sender, content)
+    }
+
+    pub fn edit(
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        target: u64,
+        content: impl Into<String>,
+    ) -> Self {
+        Self::on(MessageKind::Edit, room, sender, target, content)
+    }
+
+    pub fn delete(room: impl Into<String>, sender: impl Into<String>, target: u64) -> Self {
+        Self::on(MessageKind::Delete, room, sender, target, "")
+    }
+
+    pub fn react(
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        target: u64,
+        emoji: impl Into<String>,
+    ) -> Self {
+        Self::on(MessageKind::React, room, sender, target, emoji)
+    }
+
+    pub fn unreact(
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        target: u64,
+        emoji: impl Into<String>,
+    ) -> Self {
+        Self::on(MessageKind::Unreact, room, sender, target, emoji)
+    }
+
+    pub fn in_reply_to(self, parent: u64) -> Self {
+        Self {
+            reply_to: Some(parent),
+            ..self
+        }
+    }
+
+    pub fn by(self, author: impl Into<String>) -> Self {
+        Self {
+            author: Some(author.into()),
+            ..self
+        }
+    }
+
+    /// 可以被回复, 编辑, 删除和 react 的消息
+    pub fn is_targetable(&self) -> bool {
+        self.room.is_some()
+            && matches!(
+                self.kind,
+                MessageKind::Chat | MessageKind::Action | MessageKind::File
+            )
+    }
+
+    pub fn private(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::new(MessageKind::Private, None, sender, content)
+    }
+
+    /// 只发给一个人的服务端回复
+    pub fn reply(content: impl Into<String>) -> Self {
+        Self::new(MessageKind::System, None, SERVER_NAME, content)
+    }
+
+    pub fn replay(&self) -> Self {
+        Self {
+            replayed: true,
+            ..self.clone()
+        }
+    }
+}
+
+/// 老的按行协议就是直接发 Display 的结果
+impl Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.replayed {
+            write!(f, "{} ", self.timestamp.format("%Y-%m-%d %H:%M:%S"))?;
+        }
+        if let Some(room) = &self.room {
+            write!(f, "[{}] ", room)?;
+        }
+        // 显示 id, 编辑和回复的时候要用
+        if self.is_targetable() {
+            write!(f, "#{} ", self.id)?;
+        }
+        let target = self.target.unwrap_or_default();
+        match self.kind {
+            MessageKind::Chat => match self.reply_to {
+                Some(parent) => write!(f, "{} (re #{}):{}", self.sender, parent, self.content),
+                None => write!(f, "{}:{}", self.sender, self.content),
+            },
+            MessageKind::System => write!(f, "{}:{}", self.sender, self.content),
+            MessageKind::Action => write!(f, "* {} {}", self.sender, self.content),
+            MessageKind::File => write!(f, "* {} shared {}", self.sender, self.content),
+            MessageKind::Edit => {
+                write!(f, "* {} edited #{}: {}", self.sender, target, self.content)
+            }
+            MessageKind::Delete => write!(f, "* {} deleted #{}", self.sender, target),
+            MessageKind::React => write!(
+                f,
+                "* {} reacted {} to #{}",
+                self.sender, self.content, target
+            ),
+            MessageKind::Unreact => {
+                write!(
+                    f,
+                    "* {} removed {} from #{}",
+                    self.sender, self.content, target
+                )
+            }
+            MessageKind::Private => write!(f, "(private) {}:{}", self.sender, self.content),
+            MessageKind::Join => write!(f, "{}:Hello, {}!", SERVER_NAME, self.sender),
+            MessageKind::Leave => {
+                write!(f, "{}:{} has left the room!", SERVER_NAME, self.sender)
+            }
+        }
+    }
+}
@@ -0,0 +1,5 @@
+mod message;
+mod protocol;
+
+pub use message::{Message, MessageKind, SERVER_NAME};
+pub use protocol::{ClientFrame, FrameCodec, Payload, ServerFrame, PROTOCOL_VERSION};
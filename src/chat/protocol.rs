@@ -0,0 +1,418 @@
+use super::{Message, MessageKind};
+use crate::Error;
+use bytes::{Buf, Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+/// 大版本不一样时服务端直接拒绝
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// 和 LengthDelimitedCodec 的默认值一样
+const DEFAULT_MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// 客户端发给服务端的帧, 连接后第一帧必须是 Hello
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Hello {
+        version: u32,
+    },
+    // line 和按行协议里的一行一样, 可以是消息也可以是 `/` 命令, 处理完服务端回 Ack
+    Send {
+        id: u64,
+        line: String,
+    },
+    // 回应服务端的 Ping, nonce 原样带回
+    Pong {
+        nonce: u64,
+    },
+    // /upload 之后的文件内容, 数据是紧跟着的一个原始字节帧, 处理完服务端回 Ack
+    Chunk {
+        id: u64,
+        len: usize,
+        #[serde(skip)]
+        data: Bytes,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Welcome {
+        version: u32,
+    },
+    // 需要用户输入, 比如用户名
+    Prompt {
+        text: String,
+    },
+    Message(Message),
+    Join {
+        id: u64,
+        room: String,
+        username: String,
+        timestamp: DateTime<Utc>,
+        #[serde(default)]
+        replayed: bool,
+    },
+    Leave {
+        id: u64,
+        room: String,
+        username: String,
+        timestamp: DateTime<Utc>,
+        #[serde(default)]
+        replayed: bool,
+    },
+    // id 是出错的 Send 帧, 和具体帧无关的错误为 None
+    Error {
+        id: Option<u64>,
+        reason: String,
+    },
+    Ack {
+        id: u64,
+    },
+    // 心跳, 客户端要回 Pong, 太久没回会被断开
+    Ping {
+        nonce: u64,
+    },
+    // /download 的回应, 后面跟着若干个 Chunk, 加起来一共 size 字节
+    File {
+        hash: String,
+        name: String,
+        size: u64,
+    },
+    Chunk {
+        len: usize,
+        #[serde(skip)]
+        data: Bytes,
+    },
+}
+
+/// 帧后面可以跟一个原始字节帧, JSON 里只写长度, 文件内容不用 base64
+pub trait Payload {
+    fn payload_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn set_payload(&mut self, _data: Bytes) {}
+
+    fn take_payload(&mut self) -> Option<Bytes> {
+        None
+    }
+}
+
+impl ClientFrame {
+    pub fn chunk(id: u64, data: Bytes) -> Self {
+        ClientFrame::Chunk {
+            id,
+            len: data.len(),
+            data,
+        }
+    }
+}
+
+impl Payload for ClientFrame {
+    fn payload_len(&self) -> Option<usize> {
+        match self {
+            ClientFrame::Chunk { len, .. } => Some(*len),
+            _ => None,
+        }
+    }
+
+    fn set_payload(&mut self, payload: Bytes) {
+        if let ClientFrame::Chunk { data, .. } = self {
+            *data = payload;
+        }
+    }
+
+    fn take_payload(&mut self) -> Option<Bytes> {
+        match self {
+            ClientFrame::Chunk { data, .. } => Some(std::mem::take(data)),
+            _ => None,
+        }
+    }
+}
+
+impl From<&Message> for ServerFrame {
+    fn from(message: &Message) -> Self {
+        let room = message.room.clone().unwrap_or_default();
+        match message.kind {
+            MessageKind::Join => ServerFrame::Join {
+                id: message.id,
+                room,
+                username: message.sender.clone(),
+                timestamp: message.timestamp,
+                replayed: message.replayed,
+            },
+            MessageKind::Leave => ServerFrame::Leave {
+                id: message.id,
+                room,
+                username: message.sender.clone(),
+                timestamp: message.timestamp,
+                replayed: message.replayed,
+            },
+            _ => ServerFrame::Message(Message {
+                author: None,
+                ..message.clone()
+            }),
+        }
+    }
+}
+
+impl ServerFrame {
+    pub fn chunk(data: Bytes) -> Self {
+        ServerFrame::Chunk {
+            len: data.len(),
+            data,
+        }
+    }
+
+    /// 客户端把 Join/Leave 还原成 Message, 方便统一显示
+    pub fn into_message(self) -> Option<Message> {
+        let (kind, id, room, username, timestamp, replayed) = match self {
+            ServerFrame::Message(message) => return Some(message),
+            ServerFrame::Join {
+                id,
+                room,
+                username,
+                timestamp,
+                replayed,
+            } => (MessageKind::Join, id, room, username, timestamp, replayed),
+            ServerFrame::Leave {
+                id,
+                room,
+                username,
+                timestamp,
+                replayed,
+            } => (MessageKind::Leave, id, room, username, timestamp, replayed),
+            _ => return None,
+        };
+        Some(Message {
+            id,
+            timestamp,
+            replayed,
+            ..Message::new(kind, Some(room), username, "")
+        })
+    }
+}
+
+impl Payload for ServerFrame {
+    fn payload_len(&self) -> Option<usize> {
+        match self {
+            ServerFrame::Chunk { len, .. } => Some(*len),
+            _ => None,
+        }
+    }
+
+    fn set_payload(&mut self, payload: Bytes) {
+        if let ServerFrame::Chunk { data, .. } = self {
+            *data = payload;
+        }
+    }
+
+    fn take_payload(&mut self) -> Option<Bytes> {
+        match self {
+            ServerFrame::Chunk { data, .. } => Some(std::mem::take(data)),
+            _ => None,
+        }
+    }
+}
+
+/// 4 字节长度 + JSON, 服务端用 `FrameCodec<ClientFrame, ServerFrame>`, 客户端反过来
+#[derive(Debug)]
+pub struct FrameCodec<D, E> {
+    // 只用来编码, 解码时自己解析长度
+    inner: LengthDelimitedCodec,
+    // 只限制收到的帧, 发出去的不限制
+    max_length: usize,
+    // 已经收到 JSON, 还在等后面的原始字节帧
+    pending: Option<D>,
+    _marker: PhantomData<fn(E) -> D>,
+}
+
+impl<D, E> FrameCodec<D, E> {
+    pub fn new() -> Self {
+        Self::with_max_length(DEFAULT_MAX_FRAME_LEN)
+    }
+
+    /// 收到超过 max_length 的帧时返回 `Error::FrameTooLong`, 之后这个连接就不能再用了
+    pub fn with_max_length(max_length: usize) -> Self {
+        Self {
+            inner: LengthDelimitedCodec::builder()
+                .max_frame_length(usize::MAX)
+                .new_codec(),
+            max_length,
+            pending: None,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<D, E> Default for FrameCodec<D, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D, E> FrameCodec<D, E> {
+    fn next_frame(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        // 自己解析长度, 帧只收到一半时下次还要从头部开始看
+        let Some(head) = src.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+        // 先看长度, 不要等整个超长的帧都读进来
+        if len > self.max_length {
+            return Err(Error::FrameTooLong(self.max_length));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        Ok(Some(src.split_to(len)))
+    }
+}
+
+impl<D: DeserializeOwned + Payload, E> Decoder for FrameCodec<D, E> {
+    type Item = D;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, Error> {
+        if self.pending.is_none() {
+            let Some(frame) = self.next_frame(src)? else {
+                return Ok(None);
+            };
+            let item: D = serde_json::from_slice(&frame)?;
+            match item.payload_len() {
+                Some(len) if len > self.max_length => {
+                    return Err(Error::FrameTooLong(self.max_length))
+                }
+                Some(_) => self.pending = Some(item),
+                None => return Ok(Some(item)),
+            }
+        }
+        let Some(payload) = self.next_frame(src)? else {
+            return Ok(None);
+        };
+        let mut item = self.pending.take().expect("pending frame");
+        if item.payload_len() != Some(payload.len()) {
+            return Err(Error::Custom(format!(
+                "payload length mismatch, expect {:?}, got {}",
+                item.payload_len(),
+                payload.len()
+            )));
+        }
+        item.set_payload(payload.freeze());
+        Ok(Some(item))
+    }
+}
+
+impl<D, E: Serialize + Payload> Encoder<E> for FrameCodec<D, E> {
+    type Error = Error;
+
+    fn encode(&mut self, mut item: E, dst: &mut BytesMut) -> Result<(), Error> {
+        let payload = item.take_payload();
+        let frame = serde_json::to_vec(&item)?;
+        self.inner.encode(Bytes::from(frame), dst)?;
+        if let Some(payload) = payload {
+            self.inner.encode(payload, dst)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type ServerCodec = FrameCodec<ClientFrame, ServerFrame>;
+    type ClientCodec = FrameCodec<ServerFrame, ClientFrame>;
+
+    // 4 字节长度 + 内容
+    fn raw(data: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn encode(frame: ClientFrame) -> BytesMut {
+        let mut buf = BytesMut::new();
+        ClientCodec::new().encode(frame, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn decode_should_wait_for_whole_frame() {
+        let mut encoded = encode(ClientFrame::Send {
+            id: 1,
+            line: "hello".to_string(),
+        });
+        let mut codec = ServerCodec::new();
+        let mut src = encoded.split_to(6);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&encoded);
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+        assert!(matches!(frame, ClientFrame::Send { id: 1, line } if line == "hello"));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn chunk_should_carry_raw_payload() {
+        let data = Bytes::from_static(b"\x00\x01binary\xff");
+        let mut src = encode(ClientFrame::chunk(7, data.clone()));
+        let mut codec = ServerCodec::new();
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+        assert!(matches!(frame, ClientFrame::Chunk { id: 7, len: 9, data: d } if d == data));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn chunk_payload_can_arrive_later() {
+        let mut codec = ServerCodec::new();
+        let mut src = raw(br#"{"type":"chunk","id":1,"len":3}"#);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&raw(b"abc"));
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+        assert!(matches!(frame, ClientFrame::Chunk { data, .. } if data.as_ref() == b"abc"));
+    }
+
+    #[test]
+    fn payload_length_mismatch_should_fail() {
+        let mut codec = ServerCodec::new();
+        let mut src = raw(br#"{"type":"chunk","id":1,"len":3}"#);
+        src.extend_from_slice(&raw(b"abcd"));
+        assert!(matches!(codec.decode(&mut src), Err(Error::Custom(_))));
+    }
+
+    #[test]
+    fn too_long_frame_should_fail_before_it_arrives() {
+        let mut codec = ServerCodec::with_max_length(16);
+        // 只有长度, 内容还没到
+        let mut src = BytesMut::from(&100u32.to_be_bytes()[..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::FrameTooLong(16))
+        ));
+    }
+
+    #[test]
+    fn too_long_payload_should_fail() {
+        let mut codec = ServerCodec::with_max_length(64);
+        let mut src = raw(br#"{"type":"chunk","id":1,"len":65}"#);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::FrameTooLong(64))
+        ));
+    }
+
+    #[test]
+    fn invalid_json_should_fail() {
+        let mut codec = ServerCodec::new();
+        let mut src = raw(b"not json");
+        assert!(matches!(codec.decode(&mut src), Err(Error::Serialize(_))));
+    }
+}
@@ -8,6 +8,10 @@ pub enum Error {
     Parse(#[from] std::num::ParseIntError),
     #[error("Serialize json error: {0}")]
     Serialize(#[from] serde_json::Error),
+    #[error("Frame too long, at most {0} bytes")]
+    FrameTooLong(usize),
+    #[error("TLS error: {0}")]
+    Tls(#[from] tokio_rustls::rustls::Error),
     #[error("Custom error: {0}")]
     Custom(String),
 }
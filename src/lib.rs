@@ -1,3 +1,6 @@
+pub mod chat;
 mod error;
+pub mod shutdown;
+pub mod tls;
 
-pub use error::Error;
\ No newline at end of file
+pub use error::Error;
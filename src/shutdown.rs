@@ -0,0 +1,38 @@
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// 收到 SIGINT (Ctrl-C) 或者 SIGTERM 时 cancel, 各个 accept 循环和连接都 select 这个 token
+pub fn on_signal() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        let name = wait_signal().await;
+        info!("Received {}, shutting down", name);
+        cancel.cancel();
+    });
+    token
+}
+
+#[cfg(unix)]
+async fn wait_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::warn!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
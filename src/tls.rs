@@ -0,0 +1,263 @@
+use crate::Error;
+use axum::serve::Listener;
+use serde::Deserialize;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tracing::{info, warn};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// 握手完成但还没被取走的连接数
+const ACCEPT_BACKLOG: usize = 128;
+
+/// 证书和私钥都是 PEM 文件, 配了 client_ca 时要求客户端出示由它签发的证书
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    // 证书链, 服务端证书在最前面
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+/// 文件更新后可以重新加载, 已经建立的连接不受影响
+#[derive(Debug)]
+pub struct TlsAcceptor {
+    config: TlsConfig,
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    server: Arc<ServerConfig>,
+    // 上次加载时各个文件的修改时间
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// 在后台做 TLS 握手, 握手慢的连接不会挡住其他连接
+#[derive(Debug)]
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    receiver: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsConfig {
+    /// 从 `<prefix>_CERT`, `<prefix>_KEY`, `<prefix>_CLIENT_CA` 读取, 没配证书时返回 None
+    pub fn from_env(prefix: &str) -> Result<Option<Self>, Error> {
+        let var = |name: &str| env::var(format!("{}_{}", prefix, name)).ok();
+        match (var("CERT"), var("KEY")) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: cert.into(),
+                key: key.into(),
+                client_ca: var("CLIENT_CA").map(PathBuf::from),
+            })),
+            (None, None) => Ok(None),
+            _ => Err(Error::Custom(format!(
+                "{0}_CERT and {0}_KEY must be set together",
+                prefix
+            ))),
+        }
+    }
+
+    fn files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cert.as_path(), self.key.as_path()];
+        files.extend(self.client_ca.as_deref());
+        files
+    }
+}
+
+impl TlsAcceptor {
+    pub fn new(config: TlsConfig) -> Result<Self, Error> {
+        let modified = modified(&config);
+        let server = load(&config)?;
+        info!("Loaded TLS certificate from {}", config.cert.display());
+        Ok(Self {
+            config,
+            inner: RwLock::new(Inner { server, modified }),
+        })
+    }
+
+    pub async fn accept<IO>(&self, stream: IO) -> io::Result<TlsStream<IO>>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let server = self.inner.read().unwrap().server.clone();
+        tokio_rustls::TlsAcceptor::from(server).accept(stream).await
+    }
+
+    /// 文件的修改时间变了才重新加载, 返回是否加载了, 失败时继续用旧的证书
+    pub fn reload(&self) -> Result<bool, Error> {
+        let modified = modified(&self.config);
+        if modified == self.inner.read().unwrap().modified {
+            return Ok(false);
+        }
+        let server = load(&self.config)?;
+        *self.inner.write().unwrap() = Inner { server, modified };
+        Ok(true)
+    }
+
+    /// 定时检查证书文件, 证书续期后不用重启
+    pub async fn watch(self: Arc<Self>, period: Duration) {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            match self.reload() {
+                Ok(true) => info!(
+                    "Reloaded TLS certificate from {}",
+                    self.config.cert.display()
+                ),
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "Failed to reload TLS certificate, keep using the old one: {}",
+                    e
+                ),
+            }
+        }
+    }
+}
+
+fn modified(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    config
+        .files()
+        .into_iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn load(config: &TlsConfig) -> Result<Arc<ServerConfig>, Error> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(&config.cert, e))?;
+    if certs.is_empty() {
+        return Err(Error::Custom(format!(
+            "no certificate found in {}",
+            config.cert.display()
+        )));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key).map_err(|e| pem_error(&config.key, e))?;
+
+    // 只编译了 ring, 不依赖进程级别的默认 provider
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path).map_err(|e| pem_error(path, e))? {
+                roots.add(cert.map_err(|e| pem_error(path, e))?)?;
+            }
+            let verifier = client_verifier(roots, provider)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// 客户端用, ca 是签发服务端证书的 CA, 服务端要求客户端证书时再带上 identity (证书, 私钥)
+pub fn connector(
+    ca: &Path,
+    identity: Option<(&Path, &Path)>,
+) -> Result<tokio_rustls::TlsConnector, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).map_err(|e| pem_error(ca, e))? {
+        roots.add(cert.map_err(|e| pem_error(ca, e))?)?;
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => {
+            let certs = CertificateDer::pem_file_iter(cert)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| pem_error(cert, e))?;
+            let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+}
+
+fn client_verifier(
+    roots: RootCertStore,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn tokio_rustls::rustls::server::danger::ClientCertVerifier>, Error> {
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .build()
+        .map_err(|e| Error::Custom(format!("invalid client CA: {}", e)))
+}
+
+fn pem_error(path: &Path, e: impl std::fmt::Display) -> Error {
+    Error::Custom(format!("failed to read {}: {}", path.display(), e))
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: Arc<TlsAcceptor>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            // 一般是文件描述符用完了, 等一会再试
+                            warn!("Failed to accept connection: {}", e);
+                            sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    },
+                    // 没人再取连接时退出, 同时关掉 listener
+                    _ = sender.closed() => break,
+                };
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => warn!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            receiver,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.receiver.recv().await {
+            Some(accepted) => accepted,
+            // 后台的任务只会在 receiver 被 drop 之后退出
+            None => unreachable!("TLS accept loop exited"),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}